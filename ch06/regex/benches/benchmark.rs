@@ -3,7 +3,8 @@
 //! ## 計測方法
 //! a?^n a^nという正規表現を、a^nという文字列にマッチさせる。
 //! ただし、a?^nとa^nは、a?とaのn回の繰り返し。
-//! 計測は幅優先と深さ優先で行う。
+//! 計測は幅優先、深さ優先、Pike VM、DFAで行う。
+//! DFAは、コンパイル済みの正規表現を使い回す場合も計測する。
 //!
 //! ## n = 3の場合の例
 //!
@@ -22,7 +23,7 @@
 //! 実行後は、target/criterion/reports/index.htmlというファイルが生成されるため、
 //! それをWebブラウザで閲覧する。
 use criterion::{criterion_group, criterion_main, Criterion};
-use regex::do_matching;
+use regex::{do_matching, do_matching_with, EvalMode, Regex};
 use std::time::Duration;
 
 /// (計測のid、a?^n a^nという正規表現、文字列)というタプル
@@ -81,5 +82,46 @@ fn width_first(c: &mut Criterion) {
     }
 }
 
-criterion_group!(benches, width_first, depth_first);
+fn pike_vm(c: &mut Criterion) {
+    let mut g = c.benchmark_group("Pike VM");
+    g.measurement_time(Duration::from_secs(12));
+
+    for i in INPUTS {
+        g.bench_with_input(i.0, &(i.1, i.2), |b, args| {
+            b.iter(|| do_matching_with(args.0, args.1, EvalMode::Pike))
+        });
+    }
+}
+
+fn dfa(c: &mut Criterion) {
+    let mut g = c.benchmark_group("DFA");
+    g.measurement_time(Duration::from_secs(12));
+
+    for i in INPUTS {
+        g.bench_with_input(i.0, &(i.1, i.2), |b, args| {
+            b.iter(|| do_matching_with(args.0, args.1, EvalMode::Dfa))
+        });
+    }
+}
+
+fn dfa_compiled(c: &mut Criterion) {
+    let mut g = c.benchmark_group("DFA (compiled)");
+    g.measurement_time(Duration::from_secs(12));
+
+    for i in INPUTS {
+        let re = Regex::with_mode(i.1, EvalMode::Dfa).unwrap();
+        g.bench_with_input(i.0, &(re, i.2), |b, args| {
+            b.iter(|| args.0.is_match(args.1))
+        });
+    }
+}
+
+criterion_group!(
+    benches,
+    width_first,
+    depth_first,
+    pike_vm,
+    dfa,
+    dfa_compiled
+);
 criterion_main!(benches);
@@ -1,27 +1,116 @@
 //! 正規表現エンジン
+pub mod bytes;
+mod class;
 mod codegen;
+mod dfa;
 mod evaluator;
+mod literal;
+mod optimizer;
 mod parser;
+mod regex;
+mod set;
+mod unicode;
+mod utf8;
 
 use crate::helper::DynError;
+use class::CharClass;
 use std::fmt::{self, Display};
 
+pub use codegen::CodeGenError;
+pub use evaluator::{EvalError, EvalMode};
+pub use parser::ParseError;
+pub use regex::{
+    Captures, CapturesIter, FindIter, Match, Regex, RegexBuilder, Replacer, Split, SplitN,
+};
+pub use set::{RegexSet, RegexSetBuilder, SetMatches};
+
 /// 命令列
 #[derive(Debug)]
 pub enum Instruction {
     Char(char),
-    Match,
+    Match(usize), // マッチ成功。正規表現の集合では、マッチした正規表現の番号を持つ
     Jump(usize),
     Split(usize, usize),
+    Save(usize),          // 現在の位置をスロットに保存
+    Class(CharClass),     // 文字クラス中のいずれかの文字にマッチ
+    Any(bool),            // 任意の1文字にマッチ。falseの場合は改行にはマッチしない
+    Assert(Assertion),    // 現在の位置が条件を満たす場合のみ、文字を消費せずに次へ進む
+    Byte(u8, u8),         // 範囲内の1バイトにマッチ。バイト単位の命令列でのみ用いる
+    Str(String),          // 文字列にマッチ。連続するchar命令を最適化でまとめたもの
+    Backref(usize, bool), // n番目のグループがマッチした文字列にマッチ。trueの場合は大文字と小文字を区別しない
+    Look(Look, usize),    // 直後からmatch命令までの部分命令列を評価し、成功した場合はアドレスへ進む
+}
+
+/// 文字を消費せずに、位置に関する条件を判定するアサーション
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assertion {
+    StartText,       // 入力の先頭
+    EndText,         // 入力の末尾
+    StartLine,       // 行頭。入力の先頭もしくは改行の直後
+    EndLine,         // 行末。入力の末尾もしくは改行の直前
+    WordBoundary,    // 単語の境界。直前と直後の文字の一方のみが\\wにマッチする
+    NotWordBoundary, // 単語の境界以外
+}
+
+impl Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Assertion::StartText => write!(f, "start_text"),
+            Assertion::EndText => write!(f, "end_text"),
+            Assertion::StartLine => write!(f, "start_line"),
+            Assertion::EndLine => write!(f, "end_line"),
+            Assertion::WordBoundary => write!(f, "word_boundary"),
+            Assertion::NotWordBoundary => write!(f, "not_word_boundary"),
+        }
+    }
+}
+
+/// 先読みと後読みの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Look {
+    Ahead,            // (?=e)
+    NegAhead,         // (?!e)
+    Behind(usize),    // (?<=e)。eの長さ、すなわち読み戻す文字数を持つ
+    NegBehind(usize), // (?<!e)。eの長さ、すなわち読み戻す文字数を持つ
+}
+
+impl Look {
+    /// (?=e)や(?<=e)のように、eにマッチする場合に成功するかを判定
+    fn is_positive(&self) -> bool {
+        matches!(self, Look::Ahead | Look::Behind(_))
+    }
+}
+
+impl Display for Look {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Look::Ahead => write!(f, "ahead"),
+            Look::NegAhead => write!(f, "neg_ahead"),
+            Look::Behind(n) => write!(f, "behind {}", n),
+            Look::NegBehind(n) => write!(f, "neg_behind {}", n),
+        }
+    }
 }
 
 impl Display for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Instruction::Char(c) => write!(f, "char {}", c),
-            Instruction::Match => write!(f, "match"),
+            Instruction::Match(0) => write!(f, "match"),
+            Instruction::Match(id) => write!(f, "match {}", id),
             Instruction::Jump(addr) => write!(f, "jump {:>04}", addr),
             Instruction::Split(addr1, addr2) => write!(f, "split {:>04}, {:>04}", addr1, addr2),
+            Instruction::Save(n) => write!(f, "save {}", n),
+            Instruction::Class(cls) => write!(f, "class {}", cls),
+            Instruction::Any(true) => write!(f, "any"),
+            Instruction::Any(false) => write!(f, "any_except_nl"),
+            Instruction::Assert(a) => write!(f, "assert {}", a),
+            Instruction::Byte(lo, hi) if lo == hi => write!(f, "byte {:02x}", lo),
+            Instruction::Byte(lo, hi) => write!(f, "byte {:02x}-{:02x}", lo, hi),
+            Instruction::Str(s) => write!(f, "str {:?}", s),
+            Instruction::Backref(n, false) => write!(f, "backref {}", n),
+            Instruction::Backref(n, true) => write!(f, "backref_nocase {}", n),
+            Instruction::Look(look, addr) => write!(f, "look {}, {:>04}", look, addr),
         }
     }
 }
@@ -40,6 +129,26 @@ impl Display for Instruction {
 ///
 /// 入力された正規表現にエラーがあったり、内部的な実装エラーがある場合はErrを返す。
 pub fn print(expr: &str) -> Result<(), DynError> {
+    print_with(expr, false)
+}
+
+/// 正規表現をパースしてコード生成し、
+/// ASTと命令列を標準出力に表示。
+///
+/// optimizedがtrueの場合は、最適化前の命令列に加えて、最適化後の命令列も表示する。
+/// 最適化後の命令列は、深さ優先探索で評価する場合のもの。
+///
+/// # 利用例
+///
+/// ```
+/// use regex;
+/// regex::print_with("abc|(de|cd)+", true);
+/// ```
+///
+/// # 返り値
+///
+/// 入力された正規表現にエラーがあったり、内部的な実装エラーがある場合はErrを返す。
+pub fn print_with(expr: &str, optimized: bool) -> Result<(), DynError> {
     println!("expr: {expr}");
     let ast = parser::parse(expr)?;
     println!("AST: {:?}", ast);
@@ -47,13 +156,24 @@ pub fn print(expr: &str) -> Result<(), DynError> {
     println!();
     println!("code:");
     let code = codegen::get_code(&ast)?;
-    for (n, c) in code.iter().enumerate() {
-        println!("{:>04}: {c}", n);
+    print_code(&code);
+
+    if optimized {
+        println!();
+        println!("optimized code:");
+        print_code(&optimizer::optimize(code, true));
     }
 
     Ok(())
 }
 
+/// 命令列をアドレスとともに表示
+fn print_code(code: &[Instruction]) {
+    for (n, c) in code.iter().enumerate() {
+        println!("{:>04}: {c}", n);
+    }
+}
+
 /// 正規表現と文字列をマッチング。
 ///
 /// # 利用例
@@ -75,8 +195,36 @@ pub fn print(expr: &str) -> Result<(), DynError> {
 ///
 /// 入力された正規表現にエラーがあったり、内部的な実装エラーがある場合はErrを返す。
 pub fn do_matching(expr: &str, line: &str, is_depth: bool) -> Result<bool, DynError> {
+    let mode = if is_depth {
+        EvalMode::Depth
+    } else {
+        EvalMode::Width
+    };
+    do_matching_with(expr, line, mode)
+}
+
+/// 評価器の種類を指定して、正規表現と文字列をマッチング。
+///
+/// # 利用例
+///
+/// ```
+/// use regex::{self, EvalMode};
+/// regex::do_matching_with("abc|(de|cd)+", "decddede", EvalMode::Pike);
+/// ```
+///
+/// # 引数
+///
+/// exprに正規表現、lineにマッチ対象とする文字列を与える。
+/// modeには評価器の種類を指定する。
+/// EvalMode::Pikeを指定した場合は、どのような正規表現であっても
+/// O(命令数 × 入力長)の時間でマッチングを行う。
+///
+/// # 返り値
+///
+/// do_matchingと同じ。
+pub fn do_matching_with(expr: &str, line: &str, mode: EvalMode) -> Result<bool, DynError> {
     let ast = parser::parse(expr)?;
     let code = codegen::get_code(&ast)?;
-    let line = line.chars().collect::<Vec<char>>();
-    Ok(evaluator::eval(&code, &line, is_depth)?)
+    let code = optimizer::optimize(code, matches!(mode, EvalMode::Depth | EvalMode::Width));
+    Ok(evaluator::eval(&code, line.as_bytes(), mode)?)
 }
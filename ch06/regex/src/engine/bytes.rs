@@ -0,0 +1,319 @@
+//! バイト列を対象とする正規表現。
+//!
+//! 文字単位の命令列をUTF-8のバイト単位の命令列に変換してからマッチングを行うため、
+//! 入力をデコードしたり、文字列に変換したりする必要がない。
+//! 入力は不正なUTF-8を含んでいてもよく、その部分にはどの文字もマッチしない。
+//!
+//! # 利用例
+//!
+//! ```
+//! use regex::bytes::Regex;
+//! let re = Regex::new("あ+").unwrap();
+//! let m = re.find(b"\xff\xfe\xe3\x81\x82\xe3\x81\x82").unwrap().unwrap();
+//! assert_eq!(m.range(), 2..8);
+//! assert_eq!(m.as_bytes(), "ああ".as_bytes());
+//! ```
+use super::{
+    dfa::Dfa,
+    evaluator::{self, Config, EvalError, EvalMode},
+    regex::{slot_range, CaptureNames, Cursor, RegexBuilder, Slots},
+    Instruction,
+};
+use crate::helper::DynError;
+use std::{
+    fmt::{self, Display},
+    ops::{Index, Range},
+};
+
+/// バイト列を対象とする、コンパイル済みの正規表現。
+///
+/// RegexBuilder::build_bytesでも生成できる。
+#[derive(Debug)]
+pub struct Regex {
+    pub(super) expr: String,
+    pub(super) code: Vec<Instruction>, // バイト単位の命令列
+    pub(super) names: CaptureNames,    // 各グループの名前
+    pub(super) mode: EvalMode,         // 評価器の種類
+    pub(super) dfa: Option<Dfa>,       // EvalMode::Dfaの場合に用いるDFA
+    pub(super) config: Config,         // 探索時のフィルタと上限
+}
+
+/// マッチした範囲を表す型。位置はバイト単位のインデックス。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match<'t> {
+    text: &'t [u8],
+    start: usize,
+    end: usize,
+}
+
+impl<'t> Match<'t> {
+    fn new(text: &'t [u8], range: Range<usize>) -> Self {
+        Match {
+            text,
+            start: range.start,
+            end: range.end,
+        }
+    }
+
+    /// マッチ開始位置
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// マッチ終了位置
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// マッチした範囲
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    /// マッチしたバイト列
+    pub fn as_bytes(&self) -> &'t [u8] {
+        &self.text[self.range()]
+    }
+}
+
+impl Regex {
+    /// 正規表現をパースしてコード生成し、バイト列を対象とするRegexを生成。
+    ///
+    /// 入力された正規表現にエラーがあったり、内部的な実装エラーがある場合はErrを返す。
+    pub fn new(expr: &str) -> Result<Regex, DynError> {
+        Regex::with_mode(expr, EvalMode::Pike)
+    }
+
+    /// 評価器の種類を指定してRegexを生成。
+    ///
+    /// EvalMode::Widthを指定した場合は、Pike VMを用いる。
+    pub fn with_mode(expr: &str, mode: EvalMode) -> Result<Regex, DynError> {
+        RegexBuilder::new(expr).mode(mode).build_bytes()
+    }
+
+    /// 元の正規表現
+    pub fn as_str(&self) -> &str {
+        &self.expr
+    }
+
+    /// マッチ全体を含めたグループの数
+    pub fn captures_len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// マッチ全体を含めた各グループの名前を、グループ番号順に返すイテレータ。
+    ///
+    /// マッチ全体と、名前のないグループはNoneとなる。
+    pub fn capture_names(&self) -> impl Iterator<Item = Option<&str>> + '_ {
+        self.names.iter().map(|name| name.as_deref())
+    }
+
+    /// text中のいずれかの位置でマッチするかを判定。
+    pub fn is_match(&self, text: &[u8]) -> Result<bool, EvalError> {
+        Ok(self.shortest_match(text)?.is_some())
+    }
+
+    /// text中で最も左にあるマッチを返す。
+    pub fn find<'t>(&self, text: &'t [u8]) -> Result<Option<Match<'t>>, EvalError> {
+        self.find_at(text, 0)
+    }
+
+    /// textのstart以降で最も左にあるマッチを返す。
+    pub fn find_at<'t>(
+        &self,
+        text: &'t [u8],
+        start: usize,
+    ) -> Result<Option<Match<'t>>, EvalError> {
+        let mut slots = [None, None];
+        if self.search(text, start, false, &mut slots)? {
+            Ok(slot_range(&slots, 0).map(|r| Match::new(text, r)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// text中で最も左にあるマッチと、そのマッチでの各グループの範囲を返す。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::bytes::Regex;
+    /// let re = Regex::new("(\\w+)=(\\d+)").unwrap();
+    /// let caps = re.captures(b"\x00\x01key=42").unwrap().unwrap();
+    /// assert_eq!(&caps[1], b"key");
+    /// assert_eq!(&caps[2], b"42");
+    /// ```
+    pub fn captures<'t>(&self, text: &'t [u8]) -> Result<Option<Captures<'t>>, EvalError> {
+        self.captures_at(text, 0)
+    }
+
+    /// textのstart以降で最も左にあるマッチと、そのマッチでの各グループの範囲を返す。
+    pub fn captures_at<'t>(
+        &self,
+        text: &'t [u8],
+        start: usize,
+    ) -> Result<Option<Captures<'t>>, EvalError> {
+        let mut slots = vec![None; self.captures_len() * 2];
+        if self.search(text, start, false, &mut slots)? {
+            Ok(Some(Captures {
+                text,
+                slots: Slots::new(slots, self.names.clone()),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// text中の重なり合わないすべてのマッチを、左から順に返すイテレータを生成。
+    pub fn find_iter<'r, 't>(&'r self, text: &'t [u8]) -> FindIter<'r, 't> {
+        FindIter {
+            re: self,
+            text,
+            cursor: Cursor::default(),
+        }
+    }
+
+    /// マッチが存在する場合に、最初に見つかったマッチの終了位置を返す。
+    pub fn shortest_match(&self, text: &[u8]) -> Result<Option<usize>, EvalError> {
+        let mut slots = [None, None];
+        if self.search(text, 0, true, &mut slots)? {
+            Ok(slots[1])
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// 正規表現と評価器の種類、マッチングに用いるバイト単位の命令列を標準出力に表示。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::{EvalMode, RegexBuilder};
+    /// let re = RegexBuilder::new("あ|b")
+    ///     .case_insensitive(true)
+    ///     .mode(EvalMode::Dfa)
+    ///     .build_bytes()
+    ///     .unwrap();
+    /// re.print_code();
+    /// ```
+    pub fn print_code(&self) {
+        println!("expr: {}", self.expr);
+        println!("mode: {:?}", self.mode);
+        println!();
+        println!("code:");
+        super::print_code(&self.code);
+    }
+
+    /// 評価器を呼び出し、マッチした範囲をslotsに保存
+    fn search(
+        &self,
+        text: &[u8],
+        start: usize,
+        earliest: bool,
+        slots: &mut [Option<usize>],
+    ) -> Result<bool, EvalError> {
+        match &self.dfa {
+            Some(dfa) => dfa.search(text, start, false, earliest, slots, &self.config),
+            None => evaluator::search(
+                &self.code,
+                text,
+                start,
+                earliest,
+                slots,
+                self.mode,
+                &self.config,
+            ),
+        }
+    }
+}
+
+impl Display for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+
+/// マッチ全体と、各グループのマッチした範囲。
+///
+/// 0番目がマッチ全体を表し、n番目がn番目のグループを表す。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Captures<'t> {
+    text: &'t [u8],
+    slots: Slots,
+}
+
+impl<'t> Captures<'t> {
+    /// i番目のグループがマッチした範囲を返す。
+    ///
+    /// グループがマッチに関与しなかった場合や、iが範囲外の場合はNoneを返す。
+    pub fn get(&self, i: usize) -> Option<Match<'t>> {
+        self.slots.get(i).map(|r| Match::new(self.text, r))
+    }
+
+    /// nameという名前のグループがマッチした範囲を返す。
+    ///
+    /// グループがマッチに関与しなかった場合や、その名前のグループがない場合はNoneを返す。
+    pub fn name(&self, name: &str) -> Option<Match<'t>> {
+        self.slots.name(name).map(|r| Match::new(self.text, r))
+    }
+
+    /// マッチ全体を含めたグループの数
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// 常にfalseを返す。マッチ全体が必ず含まれるため
+    pub fn is_empty(&self) -> bool {
+        self.slots.len() == 0
+    }
+
+    /// 各グループのマッチを順に返すイテレータ
+    pub fn iter<'c>(&'c self) -> impl Iterator<Item = Option<Match<'t>>> + 'c {
+        (0..self.len()).map(|i| self.get(i))
+    }
+}
+
+/// i番目のグループがマッチしたバイト列を返す。
+///
+/// グループがマッチに関与しなかった場合はパニックする。
+impl<'t> Index<usize> for Captures<'t> {
+    type Output = [u8];
+
+    fn index(&self, i: usize) -> &[u8] {
+        self.get(i)
+            .map(|m| m.as_bytes())
+            .unwrap_or_else(|| panic!("no group at index '{i}'"))
+    }
+}
+
+/// nameという名前のグループがマッチしたバイト列を返す。
+///
+/// グループがマッチに関与しなかった場合や、その名前のグループがない場合はパニックする。
+impl<'t, 'n> Index<&'n str> for Captures<'t> {
+    type Output = [u8];
+
+    fn index(&self, name: &'n str) -> &[u8] {
+        self.name(name)
+            .map(|m| m.as_bytes())
+            .unwrap_or_else(|| panic!("no group named '{name}'"))
+    }
+}
+
+/// find_iterが返すイテレータ
+#[derive(Debug)]
+pub struct FindIter<'r, 't> {
+    re: &'r Regex,
+    text: &'t [u8],
+    cursor: Cursor,
+}
+
+impl<'r, 't> Iterator for FindIter<'r, 't> {
+    type Item = Result<Match<'t>, EvalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (re, text) = (self.re, self.text);
+        self.cursor.next(text, |start| {
+            Ok(re.find_at(text, start)?.map(|m| (m.range(), m)))
+        })
+    }
+}
@@ -0,0 +1,142 @@
+//! 文字クラスを表現するための、文字の範囲の集合
+use super::unicode;
+use std::fmt::{self, Display};
+
+/// 文字の範囲の集合。
+///
+/// 範囲は閉区間で表し、昇順に並べた上で、重複や隣接する範囲は結合して正規化する。
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CharClass {
+    ranges: Vec<(char, char)>,
+}
+
+impl CharClass {
+    /// 範囲の列から文字クラスを生成
+    pub fn new(ranges: Vec<(char, char)>) -> Self {
+        let mut cls = CharClass { ranges };
+        cls.normalize();
+        cls
+    }
+
+    /// \\dにマッチする文字。ASCIIの数字
+    pub fn digit() -> Self {
+        CharClass::new(vec![('0', '9')])
+    }
+
+    /// \\wにマッチする文字。ASCIIの英数字とアンダースコア
+    pub fn word() -> Self {
+        CharClass::new(vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')])
+    }
+
+    /// \\sにマッチする文字。UnicodeのWhite_Spaceで、全角空白も含む
+    pub fn space() -> Self {
+        CharClass::new(vec![
+            ('\t', '\r'),
+            (' ', ' '),
+            ('\u{85}', '\u{85}'),
+            ('\u{a0}', '\u{a0}'),
+            ('\u{1680}', '\u{1680}'),
+            ('\u{2000}', '\u{200a}'),
+            ('\u{2028}', '\u{2029}'),
+            ('\u{202f}', '\u{202f}'),
+            ('\u{205f}', '\u{205f}'),
+            ('\u{3000}', '\u{3000}'),
+        ])
+    }
+
+    /// 正規化済みの範囲の列
+    pub fn ranges(&self) -> &[(char, char)] {
+        &self.ranges
+    }
+
+    /// 文字cが含まれるかを二分探索で判定
+    pub fn contains(&self, c: char) -> bool {
+        self.ranges
+            .binary_search_by(|&(lo, hi)| {
+                if hi < c {
+                    std::cmp::Ordering::Less
+                } else if c < lo {
+                    std::cmp::Ordering::Greater
+                } else {
+                    std::cmp::Ordering::Equal
+                }
+            })
+            .is_ok()
+    }
+
+    /// 補集合を返す。サロゲート領域はcharとして表現できないため含まない
+    pub fn negate(&self) -> CharClass {
+        let mut ranges = Vec::new();
+        let mut lo = Some('\0');
+        for &(start, end) in self.ranges.iter() {
+            if let (Some(l), Some(h)) = (lo, prev_char(start)) {
+                if l <= h {
+                    ranges.push((l, h));
+                }
+            }
+            lo = next_char(end);
+        }
+        if let Some(l) = lo {
+            ranges.push((l, char::MAX));
+        }
+        CharClass { ranges }
+    }
+
+    /// 大文字と小文字を区別しない文字クラスを返す。
+    ///
+    /// 含まれる各文字について、単純大文字小文字変換で同一視される文字を追加する。
+    /// たとえば、kを含む場合はKとK(U+212A、ケルビン記号)を追加する。
+    pub fn case_fold(&self) -> CharClass {
+        let mut ranges = self.ranges.clone();
+        for &(lo, hi) in self.ranges.iter() {
+            ranges.extend(unicode::simple_fold(lo, hi).map(|c| (c, c)));
+        }
+        CharClass::new(ranges)
+    }
+
+    /// 範囲を昇順に並べ、重複と隣接する範囲を結合
+    fn normalize(&mut self) {
+        self.ranges.sort_unstable();
+        let mut ranges: Vec<(char, char)> = Vec::with_capacity(self.ranges.len());
+        for &(lo, hi) in self.ranges.iter() {
+            if let Some(last) = ranges.last_mut() {
+                if next_char(last.1).is_none_or(|c| lo <= c) {
+                    last.1 = last.1.max(hi);
+                    continue;
+                }
+            }
+            ranges.push((lo, hi));
+        }
+        self.ranges = ranges;
+    }
+}
+
+impl Display for CharClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[")?;
+        for &(lo, hi) in self.ranges.iter() {
+            if lo == hi {
+                write!(f, "{}", lo.escape_debug())?;
+            } else {
+                write!(f, "{}-{}", lo.escape_debug(), hi.escape_debug())?;
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+/// cの次の文字。サロゲート領域は飛ばす
+fn next_char(c: char) -> Option<char> {
+    match c {
+        '\u{d7ff}' => Some('\u{e000}'),
+        _ => char::from_u32(c as u32 + 1),
+    }
+}
+
+/// cの前の文字。サロゲート領域は飛ばす
+fn prev_char(c: char) -> Option<char> {
+    match c {
+        '\u{e000}' => Some('\u{d7ff}'),
+        _ => char::from_u32((c as u32).checked_sub(1)?),
+    }
+}
@@ -1,5 +1,5 @@
 //! ASTからコード生成を行う
-use super::{parser::AST, Instruction};
+use super::{class::CharClass, parser::AST, Instruction, Look};
 use crate::helper::safe_add;
 use std::{
     error::Error,
@@ -13,37 +13,125 @@ pub enum CodeGenError {
     FailStar,
     FailOr,
     FailQuestion,
+    FailCapture,
+    FailRepeat,
+    FailLook,
+    TooLarge(usize), // 命令数が上限を超えた。上限の値を持つ
 }
 
 impl Display for CodeGenError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "CodeGenError: {:?}", self)
+        match self {
+            CodeGenError::TooLarge(limit) => {
+                write!(f, "CodeGenError: program exceeds {limit} instructions")
+            }
+            _ => write!(f, "CodeGenError: {:?}", self),
+        }
     }
 }
 
 impl Error for CodeGenError {}
 
+/// 生成する命令数の上限のデフォルト値
+pub const DEFAULT_SIZE_LIMIT: usize = 100_000;
+
 /// コード生成器
-#[derive(Default, Debug)]
+#[derive(Debug)]
 struct Generator {
     pc: usize,
     insts: Vec<Instruction>,
+    size_limit: usize, // 生成する命令数の上限
 }
 
 /// コード生成を行う関数
 pub fn get_code(ast: &AST) -> Result<Vec<Instruction>, CodeGenError> {
-    let mut generator = Generator::default();
+    get_code_with_limit(ast, DEFAULT_SIZE_LIMIT)
+}
+
+/// 生成する命令数の上限を指定してコード生成を行う関数。
+///
+/// {n,m}による繰り返しは命令列を展開するため、命令数が大きくなりやすい。
+/// 命令数がsize_limitを超えた場合はCodeGenError::TooLargeを返す。
+pub fn get_code_with_limit(ast: &AST, size_limit: usize) -> Result<Vec<Instruction>, CodeGenError> {
+    let mut generator = Generator {
+        pc: 0,
+        insts: Vec::new(),
+        size_limit,
+    };
     generator.gen_code(ast)?;
     Ok(generator.insts)
 }
 
+/// 複数の正規表現をまとめた命令列を生成する関数。
+///
+/// i番目の正規表現のマッチはmatch i命令となる。
+/// 命令数がsize_limitを超えた場合はCodeGenError::TooLargeを返す。
+pub fn get_set_code_with_limit(
+    asts: &[AST],
+    size_limit: usize,
+) -> Result<Vec<Instruction>, CodeGenError> {
+    let mut generator = Generator {
+        pc: 0,
+        insts: Vec::new(),
+        size_limit,
+    };
+    generator.gen_set(asts)?;
+    Ok(generator.insts)
+}
+
 /// コード生成器のメソッド定義
 impl Generator {
     /// コード生成を行う関数の入り口
     fn gen_code(&mut self, ast: &AST) -> Result<(), CodeGenError> {
         self.gen_expr(ast)?;
         self.inc_pc()?;
-        self.insts.push(Instruction::Match);
+        self.insts.push(Instruction::Match(0));
+        Ok(())
+    }
+
+    /// 複数の正規表現をまとめたコード生成を行う関数の入り口。
+    ///
+    /// 以下のようなコードを生成。
+    ///
+    /// ```text
+    ///     split L1, L2
+    /// L1: e0のコード
+    ///     match 0
+    /// L2: split L3, L4
+    /// L3: e1のコード
+    ///     match 1
+    /// L4: ...
+    ///     en-1のコード
+    ///     match n-1
+    /// ```
+    fn gen_set(&mut self, asts: &[AST]) -> Result<(), CodeGenError> {
+        for (id, ast) in asts.iter().enumerate() {
+            let is_last = id + 1 == asts.len();
+
+            // split L1, L2
+            let split_addr = self.pc;
+            if !is_last {
+                self.inc_pc()?;
+                self.insts.push(Instruction::Split(self.pc, 0)); // L2を仮に0と設定
+            }
+
+            // L1: eiのコード
+            self.gen_expr(ast)?;
+
+            // match i
+            self.inc_pc()?;
+            self.insts.push(Instruction::Match(id));
+
+            // L2の値を設定
+            if !is_last {
+                if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(split_addr) {
+                    *l2 = self.pc;
+                } else {
+                    return Err(CodeGenError::FailOr);
+                }
+            }
+        }
+
         Ok(())
     }
 
@@ -52,24 +140,23 @@ impl Generator {
         match ast {
             AST::Char(c) => self.gen_char(*c)?,
             AST::Or(e1, e2) => self.gen_or(e1, e2)?,
-            AST::Plus(e) => self.gen_plus(e)?,
-            AST::Star(e1) => {
-                match &**e1 {
-                    // `(a*)*`のように`Star`が二重となっている場合にスタックオーバーフローする問題を回避するため、
-                    // このような`(((r*)*)*...*)*`を再帰的に処理して1つの`r*`へと変換する。
-                    AST::Star(_) => self.gen_expr(&e1)?,
-                    AST::Seq(e2) if e2.len() == 1 => {
-                        if let Some(e3 @ AST::Star(_)) = e2.get(0) {
-                            self.gen_expr(e3)?
-                        } else {
-                            self.gen_star(e1)?
-                        }
-                    }
-                    e => self.gen_star(&e)?,
-                }
-            }
-            AST::Question(e) => self.gen_question(e)?,
+            AST::Plus(e, greedy) => self.gen_plus(e, *greedy)?,
+            AST::Star(e, greedy) => self.gen_star_expr(e, *greedy)?,
+            AST::Question(e, greedy) => self.gen_question(e, *greedy)?,
             AST::Seq(v) => self.gen_seq(v)?,
+            AST::Capture(n, _, e) => self.gen_capture(*n, e)?,
+            AST::Class(cls, negated) => self.gen_class(cls, *negated)?,
+            AST::Any(nl) => self.gen_inst(Instruction::Any(*nl))?,
+            AST::Assert(a) => self.gen_inst(Instruction::Assert(*a))?,
+            AST::CaseInsensitive(e) => self.gen_case_insensitive(e)?,
+            AST::Backref(n) => self.gen_inst(Instruction::Backref(*n, false))?,
+            AST::Look(look, e) => self.gen_look(*look, e)?,
+            AST::Repeat {
+                e,
+                min,
+                max,
+                greedy,
+            } => self.gen_repeat(e, *min, *max, *greedy)?,
         }
 
         Ok(())
@@ -83,6 +170,32 @@ impl Generator {
         Ok(())
     }
 
+    /// 1つの命令のみからなるコードを生成
+    fn gen_inst(&mut self, inst: Instruction) -> Result<(), CodeGenError> {
+        self.insts.push(inst);
+        self.inc_pc()
+    }
+
+    /// class命令生成関数。
+    ///
+    /// 否定された文字クラスは、補集合に変換してから命令とする。
+    fn gen_class(&mut self, cls: &CharClass, negated: bool) -> Result<(), CodeGenError> {
+        let cls = if negated { cls.negate() } else { cls.clone() };
+        self.insts.push(Instruction::Class(cls));
+        self.inc_pc()
+    }
+
+    /// 大文字と小文字を区別しない後方参照のコード生成器。
+    ///
+    /// 大文字と小文字を区別せずに比較するbackref命令を生成する。
+    /// 文字と文字クラスは、パース時に大文字と小文字を同一視した文字クラスへ変換済みである。
+    fn gen_case_insensitive(&mut self, e: &AST) -> Result<(), CodeGenError> {
+        match e {
+            AST::Backref(n) => self.gen_inst(Instruction::Backref(*n, true)),
+            e => self.gen_expr(e),
+        }
+    }
+
     /// OR演算子のコード生成器。
     ///
     /// 以下のようなコードを生成。
@@ -129,57 +242,111 @@ impl Generator {
         Ok(())
     }
 
+    /// 先読みと後読みのコード生成器。
+    ///
+    /// 以下のようなコードを生成。
+    /// eのコードはmatch命令で終わる部分命令列として、look命令から評価される。
+    ///
+    /// ```text
+    ///     look kind, L1
+    ///     eのコード
+    ///     match
+    /// L1:
+    /// ```
+    fn gen_look(&mut self, look: Look, e: &AST) -> Result<(), CodeGenError> {
+        // look kind, L1
+        let look_addr = self.pc;
+        self.inc_pc()?;
+        self.insts.push(Instruction::Look(look, 0)); // L1を仮に0と設定
+
+        // eのコード
+        self.gen_expr(e)?;
+
+        // match
+        self.inc_pc()?;
+        self.insts.push(Instruction::Match(0));
+
+        // L1の値を設定
+        if let Some(Instruction::Look(_, l1)) = self.insts.get_mut(look_addr) {
+            *l1 = self.pc;
+        } else {
+            return Err(CodeGenError::FailLook);
+        }
+
+        Ok(())
+    }
+
     /// ?限量子のコード生成器。
     ///
     /// 以下のようなコードを生成
+    /// 非貪欲な場合は、splitの分岐先の優先度を逆にし、split L2, L1とする
     ///
     /// ```text
     ///     split L1, L2
     /// L1: eのコード
     /// L2:
     /// ```
-    fn gen_question(&mut self, e: &AST) -> Result<(), CodeGenError> {
+    fn gen_question(&mut self, e: &AST, greedy: bool) -> Result<(), CodeGenError> {
         // split L1, L2
         let split_addr = self.pc;
         self.inc_pc()?;
-        let split = Instruction::Split(self.pc, 0); // self.pcがL1。L2を仮に0と設定
+        let split = split(greedy, self.pc, 0); // self.pcがL1。L2を仮に0と設定
         self.insts.push(split);
 
         // L1: eのコード
         self.gen_expr(e)?;
 
         // L2の値を設定
-        if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(split_addr) {
-            *l2 = self.pc;
-            Ok(())
-        } else {
-            Err(CodeGenError::FailQuestion)
-        }
+        self.set_split_l2(split_addr, greedy, CodeGenError::FailQuestion)
     }
 
+    /// +限量子のコード生成器。
+    ///
     /// 以下のようなコードを生成
+    /// 非貪欲な場合は、splitの分岐先の優先度を逆にし、split L2, L1とする
     ///
     /// ```text
     /// L1: eのコード
     ///     split L1, L2
     /// L2:
     /// ```
-    fn gen_plus(&mut self, e: &AST) -> Result<(), CodeGenError> {
+    fn gen_plus(&mut self, e: &AST, greedy: bool) -> Result<(), CodeGenError> {
         // L1: eのコード
         let l1 = self.pc;
         self.gen_expr(e)?;
 
         // split L1, L2
         self.inc_pc()?;
-        let split = Instruction::Split(l1, self.pc); // self.pcがL2
+        let split = split(greedy, l1, self.pc); // self.pcがL2
         self.insts.push(split);
 
         Ok(())
     }
 
+    /// *限量子のコード生成を行う関数。
+    ///
+    /// 二重になった`Star`を1つにまとめた後、gen_starでコード生成を行う。
+    /// まとめるのは、内側と外側の`Star`がともに貪欲か、ともに非貪欲な場合のみ。
+    /// `(a*)*?`のように異なる場合、外側の非貪欲な繰り返しは0回で終わるため、まとめると結果が変わる。
+    fn gen_star_expr(&mut self, e1: &AST, greedy: bool) -> Result<(), CodeGenError> {
+        match e1 {
+            // `(a*)*`のように`Star`が二重となっている場合にスタックオーバーフローする問題を回避するため、
+            // このような`(((r*)*)*...*)*`を再帰的に処理して1つの`r*`へと変換する。
+            AST::Star(_, g) if *g == greedy => self.gen_expr(e1)?,
+            AST::Seq(e2) if is_star(e1, greedy) => self.gen_expr(&e2[0])?,
+            // グループで囲まれている場合も同様に、`(r*)*`を`(r*)`へと変換する。
+            // `(r*?)*?`は0回の繰り返しでグループがマッチしないため、変換しない
+            AST::Capture(_, _, e2) if greedy && is_star(e2, greedy) => self.gen_expr(e1)?,
+            e => self.gen_star(e, greedy)?,
+        }
+
+        Ok(())
+    }
+
     /// *限量子のコード生成器。
     ///
     /// 以下のようなコードを生成
+    /// 非貪欲な場合は、splitの分岐先の優先度を逆にし、split L3, L2とする
     ///
     /// ```text
     /// L1: split L2, L3
@@ -187,11 +354,37 @@ impl Generator {
     ///     jump L1
     /// L3:
     /// ```
-    fn gen_star(&mut self, e: &AST) -> Result<(), CodeGenError> {
+    ///
+    /// eが空文字列にマッチする場合は、`(e+)?`として以下のようなコードを生成する。
+    /// 上のコードでは、空文字列にマッチしたeの後のjump L1が、既に辿ったsplitに戻るため、
+    /// 繰り返しを終える分岐ではなく、文字を消費する分岐が優先されてしまう。
+    ///
+    /// ```text
+    ///     split L1, L2
+    /// L1: eのコード
+    ///     split L1, L2
+    /// L2:
+    /// ```
+    fn gen_star(&mut self, e: &AST, greedy: bool) -> Result<(), CodeGenError> {
+        if matches_empty(e) {
+            // split L1, L2
+            let split_addr = self.pc;
+            self.inc_pc()?;
+            let split = split(greedy, self.pc, 0); // self.pcがL1。L2を仮に0と設定
+            self.insts.push(split);
+
+            // L1: eのコード
+            // split L1, L2
+            self.gen_plus(e, greedy)?;
+
+            // L2の値を設定
+            return self.set_split_l2(split_addr, greedy, CodeGenError::FailStar);
+        }
+
         // L1: split L2, L3
         let l1 = self.pc;
         self.inc_pc()?;
-        let split = Instruction::Split(self.pc, 0); // self.pcがL2。L3を仮に0と設定
+        let split = split(greedy, self.pc, 0); // self.pcがL2。L3を仮に0と設定
         self.insts.push(split);
 
         // L2: eのコード
@@ -202,12 +395,83 @@ impl Generator {
         self.insts.push(Instruction::Jump(l1));
 
         // L3の値を設定
-        if let Some(Instruction::Split(_, l3)) = self.insts.get_mut(l1) {
-            *l3 = self.pc;
-            Ok(())
+        self.set_split_l2(l1, greedy, CodeGenError::FailStar)
+    }
+
+    /// グループのコード生成器。
+    ///
+    /// n番目のグループに対して以下のようなコードを生成。
+    /// スロット0と1はマッチ全体の範囲を表すために評価器が利用する。
+    ///
+    /// ```text
+    ///     save 2n
+    ///     eのコード
+    ///     save 2n + 1
+    /// ```
+    fn gen_capture(&mut self, n: usize, e: &AST) -> Result<(), CodeGenError> {
+        let slot = n.checked_mul(2).ok_or(CodeGenError::FailCapture)?;
+        self.gen_save(slot)?;
+        self.gen_expr(e)?;
+        self.gen_save(slot + 1)
+    }
+
+    /// save命令生成関数
+    fn gen_save(&mut self, n: usize) -> Result<(), CodeGenError> {
+        self.insts.push(Instruction::Save(n));
+        self.inc_pc()
+    }
+
+    /// {n}、{n,}、{n,m}のコード生成器。
+    ///
+    /// eのコードをmin回並べた後、maxがNoneの場合はe*のコードを、
+    /// そうでない場合は以下のように残りの(max - min)回分のコードを生成。
+    /// 非貪欲な場合は、splitの分岐先の優先度を逆にし、split L3, L1のようにする
+    ///
+    /// ```text
+    ///     split L1, L3
+    /// L1: eのコード
+    ///     split L2, L3
+    /// L2: eのコード
+    /// L3:
+    /// ```
+    fn gen_repeat(
+        &mut self,
+        e: &AST,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    ) -> Result<(), CodeGenError> {
+        for _ in 0..min {
+            let pc = self.pc;
+            self.gen_expr(e)?;
+
+            // (?:){n}のように命令を生成しない式は、何度繰り返しても命令を生成しない
+            if self.pc == pc {
+                break;
+            }
+        }
+
+        let max = if let Some(max) = max {
+            max
         } else {
-            Err(CodeGenError::FailStar)
+            return self.gen_star_expr(e, greedy);
+        };
+
+        // split L, L3
+        let mut splits = Vec::new();
+        for _ in min..max {
+            splits.push(self.pc);
+            self.inc_pc()?;
+            self.insts.push(split(greedy, self.pc, 0)); // L3を仮に0と設定
+            self.gen_expr(e)?;
+        }
+
+        // L3の値を設定
+        for addr in splits {
+            self.set_split_l2(addr, greedy, CodeGenError::FailRepeat)?;
         }
+
+        Ok(())
     }
 
     /// 連続する正規表現のコード生成
@@ -219,8 +483,63 @@ impl Generator {
         Ok(())
     }
 
-    /// プログラムカウンタをインクリメント
+    /// addrにあるsplit命令の、優先度の低い分岐先を現在のpcに設定。
+    ///
+    /// split命令がない場合はerrを返す。
+    fn set_split_l2(
+        &mut self,
+        addr: usize,
+        greedy: bool,
+        err: CodeGenError,
+    ) -> Result<(), CodeGenError> {
+        match self.insts.get_mut(addr) {
+            Some(Instruction::Split(_, l2)) if greedy => *l2 = self.pc,
+            Some(Instruction::Split(l2, _)) => *l2 = self.pc,
+            _ => return Err(err),
+        }
+        Ok(())
+    }
+
+    /// プログラムカウンタをインクリメント。命令数が上限を超えた場合はエラー
     fn inc_pc(&mut self) -> Result<(), CodeGenError> {
-        safe_add(&mut self.pc, &1, || CodeGenError::PCOverFlow)
+        safe_add(&mut self.pc, &1, || CodeGenError::PCOverFlow)?;
+        if self.pc > self.size_limit {
+            Err(CodeGenError::TooLarge(self.size_limit))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// 式が空文字列にマッチし得るかを判定
+fn matches_empty(ast: &AST) -> bool {
+    match ast {
+        AST::Char(_) | AST::Class(_, _) | AST::Any(_) => false,
+        AST::Assert(_) | AST::Look(_, _) | AST::Backref(_) => true,
+        AST::Star(_, _) | AST::Question(_, _) => true,
+        AST::Plus(e, _) | AST::Capture(_, _, e) | AST::CaseInsensitive(e) => matches_empty(e),
+        AST::Repeat { e, min, .. } => *min == 0 || matches_empty(e),
+        AST::Or(e1, e2) => matches_empty(e1) || matches_empty(e2),
+        AST::Seq(v) => v.iter().all(matches_empty),
+    }
+}
+
+/// `r*`もしくは`r*`のみからなる連続であり、`*`の貪欲さがgreedyと一致するかを判定
+fn is_star(ast: &AST, greedy: bool) -> bool {
+    match ast {
+        AST::Star(_, g) => *g == greedy,
+        AST::Seq(v) => matches!(v.as_slice(), [AST::Star(_, g)] if *g == greedy),
+        _ => false,
+    }
+}
+
+/// split命令を生成。
+///
+/// greedyがtrueの場合はsplit l1, l2を、falseの場合は優先度を逆にしたsplit l2, l1を返す。
+fn split(greedy: bool, l1: usize, l2: usize) -> Instruction {
+    if greedy {
+        Instruction::Split(l1, l2)
+    } else {
+        Instruction::Split(l2, l1)
     }
 }
@@ -0,0 +1,551 @@
+//! 命令列から遅延構築するDFA。
+//!
+//! 部分集合構成法により、Pike VMのスレッドリストをDFAの状態とみなす。
+//! 状態と遷移はマッチング中に必要になったものだけを構築し、キャッシュして使い回す。
+//! キャッシュのメモリ使用量が上限を超えた場合は、キャッシュを破棄し、
+//! その探索はPike VMで行う。
+//!
+//! マッチの終了位置は前向きのDFAで、開始位置は終了位置から逆向きに辿るDFAで求める。
+//!
+//! キャッシュは探索ごとにプールから取り出して専有するため、
+//! 複数のスレッドから同時に探索しても、互いの探索を待つことはない。
+use super::{
+    codegen::CodeGenError,
+    evaluator::{self, Config, EvalError, EvalMode, Steps},
+    utf8, Assertion, Instruction,
+};
+use std::{collections::HashMap, sync::Mutex};
+
+/// キャッシュのメモリ使用量の上限のデフォルト値（バイト）
+pub const DEFAULT_CACHE_LIMIT: usize = 2 * (1 << 20);
+
+/// 遷移表の列数。256種類のバイトと、入力の終端、文字の途中にある64種類の継続バイト
+const NUM_TRANS: usize = 257 + 64;
+
+/// 入力の終端を表す遷移表の列。逆向きのDFAでは入力の先頭を表す
+const EOF: usize = 256;
+
+/// 文字の途中にある継続バイト0x80を表す遷移表の列。0xbfまでが続く。
+///
+/// 前向きのDFAで、文字の途中からマッチを開始しないよう、文字の境界にあるバイトと区別する。
+const MID_CHAR: usize = 257;
+
+/// 未構築の遷移
+const UNKNOWN: usize = usize::MAX;
+
+/// 遅延構築するDFA
+#[derive(Debug)]
+pub struct Dfa {
+    code: Vec<Instruction>,   // バイト単位の命令列
+    anchored_start: bool,     // 命令列が入力の先頭でのみマッチするか
+    preds: Vec<Vec<usize>>,   // 各pcへ文字を消費せずに遷移する命令のpc。逆向きのDFAで用いる
+    cache_limit: usize,       // 前向きと逆向きのそれぞれのキャッシュのメモリ使用量の上限
+    pool: Mutex<Vec<Caches>>, // 探索中でないキャッシュ。探索するスレッドの数だけ作られる
+}
+
+/// 1回の探索で専有する、前向きと逆向きのDFAのキャッシュ
+#[derive(Debug, Default)]
+struct Caches {
+    fwd: Cache,
+    rev: Cache,
+}
+
+/// 構築済みの状態と遷移
+#[derive(Debug, Default)]
+struct Cache {
+    states: Vec<State>,
+    ids: HashMap<State, usize>, // 状態から状態番号への対応
+    trans: Vec<usize>,          // 状態番号 × NUM_TRANS + バイトで引く遷移表
+    memory: usize,              // おおよそのメモリ使用量
+}
+
+/// 直前のバイトの種類。^や\\bのような、直前の位置に関するアサーションの判定に用いる。
+///
+/// 逆向きのDFAでは直後のバイトの種類を表し、$や\\bのような直後の位置に関するアサーションの判定に用いる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Context {
+    Start,   // 入力の先頭
+    End,     // 入力の終端。逆向きのDFAでのみ用いる
+    NewLine, // 改行の直後
+    Word,    // \\wにマッチするバイトの直後
+    Other,
+}
+
+impl Context {
+    /// 直前のバイトbから種類を求める。bがNoneの場合は入力の先頭
+    fn new(b: Option<u8>) -> Self {
+        match b {
+            None => Context::Start,
+            Some(b'\n') => Context::NewLine,
+            Some(b) if evaluator::is_word_byte(b) => Context::Word,
+            Some(_) => Context::Other,
+        }
+    }
+}
+
+/// DFAの状態。
+///
+/// 逆向きのDFAでは、pcsは逆向きに1バイトを消費した直後のpcを昇順に並べたもの、
+/// ctxは直後のバイトの種類で、is_matchは直後の位置からマッチが開始しうることを表す。
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct State {
+    pcs: Vec<usize>, // 1バイトを消費した直後のスレッドのpc。優先度順に並ぶ
+    ctx: Context,    // 直前のバイトの種類
+    seeding: bool,   // trueの場合、この位置から始まるスレッドを最も低い優先度で追加
+    is_match: bool,  // 直前の位置でマッチが終了した
+}
+
+impl State {
+    /// これ以上マッチする可能性のない状態であるかを判定
+    fn is_dead(&self) -> bool {
+        self.pcs.is_empty() && !self.seeding
+    }
+}
+
+impl Dfa {
+    /// 命令列からDFAを生成。
+    ///
+    /// 文字単位の命令列は、バイト単位の命令列に変換してから用いる。
+    /// 変換後の命令数がsize_limitを超えた場合はCodeGenError::TooLargeを返す。
+    pub fn new(
+        code: &[Instruction],
+        cache_limit: usize,
+        size_limit: usize,
+    ) -> Result<Dfa, CodeGenError> {
+        let code = utf8::compile(code, size_limit)?;
+
+        let mut preds = vec![Vec::new(); code.len() + 1];
+        for (pc, inst) in code.iter().enumerate() {
+            match inst {
+                Instruction::Jump(addr) => preds[*addr].push(pc),
+                Instruction::Split(addr1, addr2) => {
+                    preds[*addr1].push(pc);
+                    preds[*addr2].push(pc);
+                }
+                Instruction::Save(_) | Instruction::Assert(_) => preds[pc + 1].push(pc),
+                _ => (),
+            }
+        }
+
+        Ok(Dfa {
+            anchored_start: evaluator::is_anchored_start(&code),
+            code,
+            preds,
+            cache_limit,
+            pool: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// lineのstart以降から、最も左の位置にあるマッチを探索する。
+    ///
+    /// マッチの有無と終了位置は前向きのDFAで求め、マッチが見つかった場合に、
+    /// 開始位置は終了位置から逆向きのDFAで求める。
+    /// グループの範囲が必要な場合は、求めた開始位置から始まるマッチをPike VMで求める。
+    /// ただし、earliestがtrueの場合は開始位置を求めず、slots[1]にのみ終了位置を保存する。
+    /// anchoredがtrueの場合は、startから始まるマッチのみを探索する。
+    /// そうでない場合は、config.prefilterで求めたマッチが始まりうる位置から探索を開始する。
+    /// DFAの遷移回数やPike VMの実行がconfig.step_limitを超えた場合はErrを返す。
+    pub fn search(
+        &self,
+        line: &[u8],
+        start: usize,
+        anchored: bool,
+        earliest: bool,
+        slots: &mut [Option<usize>],
+        config: &Config,
+    ) -> Result<bool, EvalError> {
+        // 探索中にパニックしたスレッドのキャッシュはプールに戻らないため、プールは常に一貫した状態にある
+        let pool = || self.pool.lock().unwrap_or_else(|e| e.into_inner());
+        let mut caches = pool().pop().unwrap_or_default();
+        let result = self.search_with(&mut caches, line, start, anchored, earliest, slots, config);
+        pool().push(caches);
+        result
+    }
+
+    /// cachesを用いて探索を行う。引数と返り値はsearchと同じ
+    #[allow(clippy::too_many_arguments)]
+    fn search_with(
+        &self,
+        caches: &mut Caches,
+        line: &[u8],
+        start: usize,
+        anchored: bool,
+        earliest: bool,
+        slots: &mut [Option<usize>],
+        config: &Config,
+    ) -> Result<bool, EvalError> {
+        evaluator::check_supported(&self.code, EvalMode::Dfa)?;
+        let start = if anchored {
+            start
+        } else {
+            match config.prefilter.start(line, start) {
+                Some(s) => s,
+                None => return Ok(false),
+            }
+        };
+        let anchored = anchored || self.anchored_start;
+        if self.anchored_start && start > 0 {
+            return Ok(false);
+        }
+
+        let end = match self.find_end(
+            &mut caches.fwd,
+            line,
+            start,
+            anchored,
+            earliest,
+            config.step_limit,
+        )? {
+            Some(Some(end)) => end,
+            Some(None) => return Ok(false),
+            // キャッシュの上限を超えた場合はPike VMで探索
+            None => {
+                return evaluator::eval_pike(
+                    &self.code, line, start, anchored, earliest, slots, config,
+                )
+            }
+        };
+
+        if earliest {
+            if let Some(s) = slots.get_mut(1) {
+                *s = Some(end);
+            }
+            return Ok(true);
+        }
+
+        let begin = match self.find_start(&mut caches.rev, line, start, end, config.step_limit)? {
+            Some(Some(begin)) => begin,
+            // キャッシュの上限を超えた場合はPike VMで探索
+            _ => {
+                return evaluator::eval_pike(
+                    &self.code, line, start, anchored, earliest, slots, config,
+                )
+            }
+        };
+
+        if slots.len() <= 2 {
+            for (slot, pos) in slots.iter_mut().zip([begin, end]) {
+                *slot = Some(pos);
+            }
+            Ok(true)
+        } else {
+            evaluator::eval_pike(&self.code, line, begin, true, earliest, slots, config)
+        }
+    }
+
+    /// DFAでマッチの終了位置を求める。
+    ///
+    /// マッチしない場合はOk(Some(None))を、キャッシュの上限を超えた場合はOk(None)を返す。
+    /// 遷移回数がstep_limitを超えた場合はErrを返す。
+    fn find_end(
+        &self,
+        cache: &mut Cache,
+        line: &[u8],
+        start: usize,
+        anchored: bool,
+        earliest: bool,
+        step_limit: usize,
+    ) -> Result<Option<Option<usize>>, EvalError> {
+        let start_state = State {
+            pcs: Vec::new(),
+            ctx: Context::new(start.checked_sub(1).and_then(|i| line.get(i).copied())),
+            seeding: true,
+            is_match: false,
+        };
+        let mut s = match self.intern(cache, start_state) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let step = |state: &State, b| self.step(state, b, anchored);
+
+        let mut steps = Steps::new(step_limit);
+        let mut last_match = None;
+        let mut next_char = start; // 次の文字が始まる位置
+        for (sp, b) in line.iter().enumerate().skip(start) {
+            steps.add(1)?;
+            let b = if sp == next_char {
+                next_char += evaluator::decode_char(line, sp).map_or(1, |(_, len)| len);
+                *b as usize
+            } else {
+                MID_CHAR + (*b - 0x80) as usize
+            };
+            s = match self.next_state(cache, s, b, step) {
+                Some(s) => s,
+                None => return Ok(None),
+            };
+            let state = &cache.states[s];
+            if state.is_match {
+                last_match = Some(sp);
+                if earliest {
+                    return Ok(Some(last_match));
+                }
+            }
+            if state.is_dead() {
+                return Ok(Some(last_match));
+            }
+        }
+
+        s = match self.next_state(cache, s, EOF, step) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        if cache.states[s].is_match {
+            last_match = Some(line.len());
+        }
+        Ok(Some(last_match))
+    }
+
+    /// 逆向きのDFAで、endで終わるマッチの開始位置のうち、start以降で最も左にあるものを求める。
+    ///
+    /// endからstartまで逆向きに1バイトずつ遷移し、マッチが開始しうる最後の位置を開始位置とする。
+    /// 最も左から始まるマッチの終了位置がendであるため、この位置がそのマッチの開始位置となる。
+    /// マッチしない場合はOk(Some(None))を、キャッシュの上限を超えた場合はOk(None)を返す。
+    /// 遷移回数がstep_limitを超えた場合はErrを返す。
+    fn find_start(
+        &self,
+        cache: &mut Cache,
+        line: &[u8],
+        start: usize,
+        end: usize,
+        step_limit: usize,
+    ) -> Result<Option<Option<usize>>, EvalError> {
+        let end_state = State {
+            pcs: (0..self.code.len())
+                .filter(|pc| matches!(self.code[*pc], Instruction::Match(_)))
+                .collect(),
+            ctx: line
+                .get(end)
+                .map_or(Context::End, |b| Context::new(Some(*b))),
+            seeding: false,
+            is_match: false,
+        };
+        let mut s = match self.intern(cache, end_state) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let step = |state: &State, b| self.step_rev(state, b);
+
+        let mut steps = Steps::new(step_limit);
+        let mut first_match = None;
+        for sp in (start..end).rev() {
+            steps.add(1)?;
+            s = match self.next_state(cache, s, line[sp] as usize, step) {
+                Some(s) => s,
+                None => return Ok(None),
+            };
+            let state = &cache.states[s];
+            // 文字の途中から始まるマッチは、他の評価器と同様に採用しない
+            if state.is_match && evaluator::is_char_boundary(line, sp + 1) {
+                first_match = Some(sp + 1);
+            }
+            if state.is_dead() {
+                return Ok(Some(first_match));
+            }
+        }
+
+        // startの直前のバイトは、アサーションの判定にのみ用いる
+        let b = start.checked_sub(1).map_or(EOF, |i| line[i] as usize);
+        s = match self.next_state(cache, s, b, step) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        if cache.states[s].is_match {
+            first_match = Some(start);
+        }
+        Ok(Some(first_match))
+    }
+
+    /// 状態sからバイトbで遷移した先の状態番号を返す。
+    ///
+    /// bがEOFの場合は入力の終端での遷移となる。
+    /// 未構築の場合はstepで遷移先の状態を求め、キャッシュする。
+    fn next_state(
+        &self,
+        cache: &mut Cache,
+        s: usize,
+        b: usize,
+        step: impl Fn(&State, usize) -> State,
+    ) -> Option<usize> {
+        let next = cache.trans[s * NUM_TRANS + b];
+        if next != UNKNOWN {
+            return Some(next);
+        }
+
+        let state = step(&cache.states[s], b);
+        let next = self.intern(cache, state)?;
+        cache.trans[s * NUM_TRANS + b] = next;
+        Some(next)
+    }
+
+    /// 状態stateの各スレッドを、バイトbで1ステップ進めた状態を求める。
+    ///
+    /// Pike VMのadd_threadと同様に、文字を消費しない命令を優先度順に辿る。
+    /// $や\\bのような直後の位置に関するアサーションは、bを先読みして判定する。
+    /// match命令に到達した場合は、それより優先度の低いスレッドを破棄する。
+    /// bがMID_CHAR以降の場合は文字の途中にある継続バイトであり、新たなマッチを開始しない。
+    fn step(&self, state: &State, b: usize, anchored: bool) -> State {
+        let (b, seeding) = match b.checked_sub(MID_CHAR) {
+            Some(i) => (0x80 + i, false),
+            None => (b, state.seeding),
+        };
+        let mut visited = vec![false; self.code.len()];
+        let mut added = vec![false; self.code.len() + 1]; // pcsに追加済みのpc
+        let mut pcs = Vec::new();
+        let mut stack = Vec::new();
+        let mut is_match = false;
+
+        let seed = seeding.then_some(0);
+        'threads: for pc in state.pcs.iter().copied().chain(seed) {
+            stack.push(pc);
+            while let Some(pc) = stack.pop() {
+                if std::mem::replace(&mut visited[pc], true) {
+                    continue;
+                }
+
+                match &self.code[pc] {
+                    Instruction::Jump(addr) => stack.push(*addr),
+                    Instruction::Split(addr1, addr2) => {
+                        stack.push(*addr2);
+                        stack.push(*addr1);
+                    }
+                    Instruction::Save(_) => stack.push(pc + 1),
+                    Instruction::Assert(a) => {
+                        if check_assert(*a, state.ctx, b) {
+                            stack.push(pc + 1);
+                        }
+                    }
+                    Instruction::Byte(lo, hi) => {
+                        if (*lo as usize..=*hi as usize).contains(&b)
+                            && !std::mem::replace(&mut added[pc + 1], true)
+                        {
+                            pcs.push(pc + 1);
+                        }
+                    }
+                    Instruction::Match(_) => {
+                        // 優先度の低いスレッドは破棄
+                        is_match = true;
+                        break 'threads;
+                    }
+                    // 空の文字クラスはどのバイトにもマッチしない
+                    // それ以外の文字単位の命令は、バイト単位の命令列には現れない
+                    // 後方参照や先読み、後読みを含む命令列は、探索の前にエラーとしている
+                    Instruction::Char(_)
+                    | Instruction::Class(_)
+                    | Instruction::Any(_)
+                    | Instruction::Str(_)
+                    | Instruction::Backref(_, _)
+                    | Instruction::Look(_, _) => (),
+                }
+            }
+        }
+
+        State {
+            pcs,
+            ctx: u8::try_from(b).map_or(Context::Other, |b| Context::new(Some(b))),
+            seeding: state.seeding && !is_match && !anchored,
+            is_match,
+        }
+    }
+
+    /// 逆向きのDFAで、状態stateの位置から、直前のバイトbを1つ消費した状態を求める。
+    ///
+    /// stateのpcsから、文字を消費しない命令を遷移元へと逆向きに辿る。
+    /// 命令列の先頭に到達した場合は、stateの位置からマッチが開始しうる。
+    /// ^や\\bのような直前の位置に関するアサーションは、bを先読みして判定する。
+    /// bがEOFの場合は入力の先頭であり、バイトを消費しない。
+    fn step_rev(&self, state: &State, b: usize) -> State {
+        let mut visited = vec![false; self.code.len() + 1];
+        let mut pcs = Vec::new();
+        let mut stack = state.pcs.clone();
+        let mut is_match = false;
+
+        while let Some(pc) = stack.pop() {
+            if std::mem::replace(&mut visited[pc], true) {
+                continue;
+            }
+            if pc == 0 {
+                is_match = true;
+            }
+
+            // 直前のbyte命令がbにマッチする場合は、bを消費してその命令へ戻る
+            if let Some(Instruction::Byte(lo, hi)) = pc.checked_sub(1).map(|i| &self.code[i]) {
+                if (*lo as usize..=*hi as usize).contains(&b) {
+                    pcs.push(pc - 1);
+                }
+            }
+
+            for &prev in self.preds[pc].iter() {
+                match &self.code[prev] {
+                    Instruction::Assert(a) if !check_assert_rev(*a, b, state.ctx) => (),
+                    _ => stack.push(prev),
+                }
+            }
+        }
+
+        pcs.sort_unstable();
+        pcs.dedup();
+        State {
+            pcs,
+            ctx: u8::try_from(b).map_or(Context::Start, |b| Context::new(Some(b))),
+            seeding: false,
+            is_match,
+        }
+    }
+
+    /// 状態をキャッシュに追加し、状態番号を返す。
+    ///
+    /// メモリ使用量が上限を超えた場合は、キャッシュを破棄してNoneを返す。
+    fn intern(&self, cache: &mut Cache, state: State) -> Option<usize> {
+        if let Some(id) = cache.ids.get(&state) {
+            return Some(*id);
+        }
+
+        // 状態はstatesとidsの両方に保持する
+        cache.memory += NUM_TRANS * std::mem::size_of::<usize>()
+            + 2 * (std::mem::size_of::<State>() + state.pcs.len() * std::mem::size_of::<usize>());
+        if cache.memory > self.cache_limit {
+            *cache = Cache::default();
+            return None;
+        }
+
+        let id = cache.states.len();
+        cache.states.push(state.clone());
+        cache.ids.insert(state, id);
+        cache.trans.resize(cache.trans.len() + NUM_TRANS, UNKNOWN);
+        Some(id)
+    }
+}
+
+/// アサーションの条件を、直前のバイトの種類ctxと直後のバイトbから判定
+fn check_assert(a: Assertion, ctx: Context, b: usize) -> bool {
+    match a {
+        Assertion::StartText => ctx == Context::Start,
+        Assertion::EndText => b == EOF,
+        Assertion::StartLine => ctx == Context::Start || ctx == Context::NewLine,
+        Assertion::EndLine => b == EOF || b == b'\n' as usize,
+        Assertion::WordBoundary => is_word_boundary(ctx, b),
+        Assertion::NotWordBoundary => !is_word_boundary(ctx, b),
+    }
+}
+
+/// 逆向きのDFAで、アサーションの条件を、直前のバイトbと直後のバイトの種類ctxから判定。
+///
+/// bがEOFの場合は入力の先頭となる。
+fn check_assert_rev(a: Assertion, b: usize, ctx: Context) -> bool {
+    match a {
+        Assertion::StartText => b == EOF,
+        Assertion::EndText => ctx == Context::End,
+        Assertion::StartLine => b == EOF || b == b'\n' as usize,
+        Assertion::EndLine => ctx == Context::End || ctx == Context::NewLine,
+        Assertion::WordBoundary => is_word_boundary(ctx, b),
+        Assertion::NotWordBoundary => !is_word_boundary(ctx, b),
+    }
+}
+
+/// 直前のバイトの種類ctxと直後のバイトbの間が、単語の境界であるかを判定。
+///
+/// 単語の境界は前後について対称であるため、逆向きのDFAでは、ctxを直後のバイトの種類、bを直前のバイトとして用いる。
+fn is_word_boundary(ctx: Context, b: usize) -> bool {
+    let after = u8::try_from(b).is_ok_and(evaluator::is_word_byte);
+    (ctx == Context::Word) != after
+}
@@ -1,8 +1,12 @@
 //! 命令列と入力文字列を受け取り、マッチングを行う
-use super::Instruction;
+use super::{
+    dfa::{self, Dfa},
+    literal::Prefilter,
+    unicode, Assertion, Instruction, Look,
+};
 use crate::helper::safe_add;
 use std::{
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
     error::Error,
     fmt::{self, Display},
 };
@@ -13,73 +17,694 @@ pub enum EvalError {
     SPOverFlow,
     InvalidPC,
     InvalidContext,
+    StepLimit(usize),    // 実行したステップ数が上限を超えた。上限の値を持つ
+    StackLimit(usize),   // バックトラック用のスタックの長さが上限を超えた。上限の値を持つ
+    VisitedLimit(usize), // 訪問済みの(pc, sp)を記録するビット集合が上限を超えた。上限の値を持つ
+    UnsupportedBackref,  // 後方参照を含む命令列を、深さ優先探索以外で評価しようとした
+    UnsupportedLook,     // 先読みや後読みを含む命令列を、深さ優先探索以外で評価しようとした
 }
 
 impl Display for EvalError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "CodeGenError: {:?}", self)
+        match self {
+            EvalError::StepLimit(limit) => {
+                write!(f, "EvalError: execution exceeds {limit} steps")
+            }
+            EvalError::StackLimit(limit) => {
+                write!(f, "EvalError: backtracking stack exceeds {limit} entries")
+            }
+            EvalError::VisitedLimit(limit) => {
+                write!(
+                    f,
+                    "EvalError: backtracking visited set exceeds {limit} bytes"
+                )
+            }
+            EvalError::UnsupportedBackref => {
+                write!(
+                    f,
+                    "EvalError: backreferences are supported only in depth-first mode"
+                )
+            }
+            EvalError::UnsupportedLook => {
+                write!(
+                    f,
+                    "EvalError: lookaround is supported only in depth-first mode"
+                )
+            }
+            _ => write!(f, "EvalError: {:?}", self),
+        }
     }
 }
 
 impl Error for EvalError {}
 
+/// 評価器の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvalMode {
+    Depth, // 深さ優先探索。訪問済みの(pc, sp)を記録し、入力長に対して線形時間でマッチング
+    Width, // 幅優先探索
+    Pike,  // Pike VM。入力長に対して線形時間でマッチング
+    Dfa,   // 遅延構築するDFA。キャッシュの上限を超えた場合はPike VMを用いる
+}
+
+/// 深さ優先探索で、訪問済みの(pc, sp)を記録するビット集合のメモリ使用量の上限のデフォルト値（バイト）
+pub const DEFAULT_VISITED_LIMIT: usize = 64 * (1 << 20);
+
+/// 探索時の設定
+#[derive(Debug)]
+pub struct Config {
+    pub prefilter: Prefilter, // マッチしえない位置を読み飛ばすためのフィルタ
+    pub step_limit: usize,    // 1回の探索で評価器が進めるステップ数の上限
+    pub stack_limit: usize,   // 深さ優先探索でバックトラック用のスタックの長さの上限
+    pub visited_limit: usize, // 深さ優先探索で訪問済みの(pc, sp)を記録するビット集合のバイト数の上限
+}
+
+impl Default for Config {
+    /// フィルタなし、ステップ数とスタックの長さの上限なしの設定
+    fn default() -> Self {
+        Config {
+            prefilter: Prefilter::default(),
+            step_limit: usize::MAX,
+            stack_limit: usize::MAX,
+            visited_limit: DEFAULT_VISITED_LIMIT,
+        }
+    }
+}
+
+/// 評価器が進めたステップ数を数え、上限を超えた場合はエラーとする。
+///
+/// 1ステップは、深さ優先探索では1命令の実行、Pike VMでは1スレッドを1文字進めること、
+/// DFAでは1バイトの遷移となる。
+#[derive(Debug)]
+pub struct Steps {
+    count: usize,
+    limit: usize,
+}
+
+impl Steps {
+    pub fn new(limit: usize) -> Self {
+        Steps { count: 0, limit }
+    }
+
+    /// ステップ数にnを加える
+    pub fn add(&mut self, n: usize) -> Result<(), EvalError> {
+        self.count = self.count.saturating_add(n);
+        if self.count > self.limit {
+            Err(EvalError::StepLimit(self.limit))
+        } else {
+            Ok(())
+        }
+    }
+}
+
 /// 命令列の評価を行う関数。
 ///
 /// instが命令列となり、その命令列を用いて入力文字列lineにマッチさせる。
-/// is_depthがtrueの場合に深さ優先探索を、falseの場合に幅優先探索を行う。
+/// lineはUTF-8でエンコードされたバイト列で、先頭からのマッチングを行う。
+/// バイト単位の命令列の場合、lineは不正なUTF-8を含んでいてもよい。
+/// modeで評価器の種類を指定する。
 ///
 /// 実行時エラーが起きた場合はErrを返す。
+/// 後方参照や先読み、後読みを含む命令列は、EvalMode::Depth以外ではErrとなる。
 /// マッチ成功時はOk(true)を、失敗時はOk(false)を返す。
-pub fn eval(inst: &[Instruction], line: &[char], is_depth: bool) -> Result<bool, EvalError> {
-    if is_depth {
-        eval_depth(inst, line, 0, 0)
+pub fn eval(inst: &[Instruction], line: &[u8], mode: EvalMode) -> Result<bool, EvalError> {
+    check_supported(inst, mode)?;
+    match mode {
+        EvalMode::Depth => {
+            let config = Config::default();
+            let mut visited = Visited::new(inst, line.len(), config.visited_limit)?;
+            let mut slots = vec![None; backref_slots(inst)];
+            let mut backtrack = Backtrack::new(inst, line, &config);
+            Ok(backtrack.run(0, 0, &mut slots, &mut visited)?.is_some())
+        }
+        EvalMode::Width => eval_width(inst, line),
+        EvalMode::Pike => eval_pike(inst, line, 0, true, true, &mut [], &Config::default()),
+        EvalMode::Dfa => {
+            // 命令数の上限を指定しないため、バイト単位の命令列への変換は失敗しない
+            let dfa =
+                Dfa::new(inst, dfa::DEFAULT_CACHE_LIMIT, usize::MAX).expect("size is unlimited");
+            dfa.search(line, 0, true, true, &mut [], &Config::default())
+        }
+    }
+}
+
+/// lineのstart以降から、最も左の位置にあるマッチを探索する。
+///
+/// 同じ位置から始まるマッチが複数ある場合は、splitの1つ目の分岐先を優先したものを返す。
+/// earliestがtrueの場合は、最初にmatch命令へ到達した時点で探索を打ち切る。
+/// EvalMode::Depthの場合は深さ優先探索を、EvalMode::DfaはDFAを、
+/// それ以外の場合はPike VMを用いる。
+/// EvalMode::Dfaの場合、DFAはこの探索のためだけに構築する。
+/// config.prefilterでマッチしえないと判定できた位置は、評価器を実行せずに読み飛ばす。
+/// 評価器の実行がconfigで指定した上限を超えた場合はErrを返す。
+///
+/// マッチした場合はOk(true)を返し、slotsにマッチした範囲を保存する。
+/// slots[0]とslots[1]がマッチ全体の開始位置と終了位置、
+/// slots[2n]とslots[2n + 1]がn番目のグループの開始位置と終了位置となる。
+/// 位置はいずれもlineのバイト単位のインデックスで、
+/// slotsの長さを超える番号のスロットへの保存は無視する。
+pub fn search(
+    inst: &[Instruction],
+    line: &[u8],
+    start: usize,
+    earliest: bool,
+    slots: &mut [Option<usize>],
+    mode: EvalMode,
+    config: &Config,
+) -> Result<bool, EvalError> {
+    check_supported(inst, mode)?;
+    if mode == EvalMode::Dfa {
+        // 命令数の上限を指定しないため、バイト単位の命令列への変換は失敗しない
+        let dfa = Dfa::new(inst, dfa::DEFAULT_CACHE_LIMIT, usize::MAX).expect("size is unlimited");
+        return dfa.search(line, start, false, earliest, slots, config);
+    }
+
+    // 先頭でのみマッチする命令列は、先頭以外からの探索を行わない
+    let anchored = is_anchored_start(inst);
+    let start = match config.prefilter.start(line, start) {
+        Some(s) if !anchored || s == 0 => s,
+        _ => return Ok(false),
+    };
+
+    if mode == EvalMode::Depth {
+        search_depth(inst, line, start, anchored, slots, config)
+    } else {
+        eval_pike(inst, line, start, anchored, earliest, slots, config)
+    }
+}
+
+/// 深さ優先探索でのみ扱える命令を、それ以外の評価器で評価しようとした場合はErrを返す。
+///
+/// 後方参照はグループがマッチした文字列に依存するため、
+/// スレッドや状態をpcのみで区別する線形時間の評価器では扱えない。
+/// 先読みや後読みは部分命令列の評価を必要とするため、同様に扱えない。
+pub fn check_supported(inst: &[Instruction], mode: EvalMode) -> Result<(), EvalError> {
+    if mode == EvalMode::Depth {
+        Ok(())
+    } else if backref_slots(inst) > 0 {
+        Err(EvalError::UnsupportedBackref)
+    } else if inst.iter().any(|i| matches!(i, Instruction::Look(_, _))) {
+        Err(EvalError::UnsupportedLook)
+    } else {
+        Ok(())
+    }
+}
+
+/// 後方参照の評価に必要なスロットの数。後方参照を含まない場合は0
+fn backref_slots(inst: &[Instruction]) -> usize {
+    inst.iter()
+        .filter_map(|i| match i {
+            Instruction::Backref(n, _) => Some(n * 2 + 2),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// lineのsp位置にあるUTF-8文字をデコードし、文字とそのバイト数を返す。
+///
+/// spが終端の場合や、不正なUTF-8の場合はNoneを返す。
+pub fn decode_char(line: &[u8], sp: usize) -> Option<(char, usize)> {
+    let len = match *line.get(sp)? {
+        b @ 0..=0x7f => return Some((b as char, 1)),
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return None,
+    };
+    let s = std::str::from_utf8(line.get(sp..sp + len)?).ok()?;
+    s.chars().next().map(|c| (c, len))
+}
+
+/// lineのsp位置が、UTF-8の文字の途中でないかを判定。
+///
+/// 不正なUTF-8の部分では、各バイトの前を文字の境界とみなす。
+/// マッチはどの評価器でも文字の境界からのみ開始するため、空文字列へのマッチも文字の途中には現れない。
+pub fn is_char_boundary(line: &[u8], sp: usize) -> bool {
+    match line.get(sp) {
+        // 継続バイトは、直前の3バイト以内から始まる正しいUTF-8の文字に含まれる場合のみ文字の途中
+        Some(0x80..=0xbf) => (sp.saturating_sub(3)..sp)
+            .all(|i| decode_char(line, i).is_none_or(|(_, len)| i + len <= sp)),
+        _ => true,
+    }
+}
+
+/// 文字を消費する命令が、lineのsp位置にマッチするかを判定。
+///
+/// マッチした場合は消費するバイト数を返す。
+/// byte命令は1バイトを、str命令は文字列全体を、それ以外の命令はUTF-8の1文字を消費する。
+fn match_inst(inst: &Instruction, line: &[u8], sp: usize) -> Option<usize> {
+    match inst {
+        Instruction::Byte(lo, hi) => {
+            let b = line.get(sp)?;
+            return (lo..=hi).contains(&b).then_some(1);
+        }
+        Instruction::Str(s) => {
+            let s = s.as_bytes();
+            return line.get(sp..)?.starts_with(s).then_some(s.len());
+        }
+        _ => (),
+    }
+
+    let (c, len) = decode_char(line, sp)?;
+    let matched = match inst {
+        Instruction::Char(i_c) => *i_c == c,
+        Instruction::Class(cls) => cls.contains(c),
+        Instruction::Any(nl) => *nl || c != '\n',
+        _ => false,
+    };
+    matched.then_some(len)
+}
+
+/// byte命令を含む、バイト単位の命令列であるかを判定
+fn is_byte_program(inst: &[Instruction]) -> bool {
+    inst.iter().any(|i| matches!(i, Instruction::Byte(_, _)))
+}
+
+/// spから次にマッチを試みる位置までのバイト数。
+///
+/// バイト単位の命令列では1バイトずつ、それ以外では1文字ずつ進める。
+/// 不正なUTF-8の場合は1バイト進める。
+fn step_len(line: &[u8], sp: usize, bytes: bool) -> usize {
+    if bytes {
+        1
     } else {
-        eval_width(inst, line)
+        decode_char(line, sp).map_or(1, |(_, len)| len)
     }
 }
 
-/// 深さ優先探索で再帰的にマッチングを行う評価器
-fn eval_depth(
+/// n番目のグループがマッチした文字列が、lineのsp位置にマッチするかを判定。
+///
+/// マッチした場合は消費するバイト数を返す。
+/// グループがマッチに関与していない場合はマッチしない。
+/// nocaseがtrueの場合は、単純大文字小文字変換で同一視される文字を等しいとして1文字ずつ比較し、
+/// 不正なUTF-8の部分はバイト単位で比較する。
+fn match_backref(
+    line: &[u8],
+    sp: usize,
+    slots: &[Option<usize>],
+    n: usize,
+    nocase: bool,
+) -> Option<usize> {
+    let (start, end) = ((*slots.get(n * 2)?)?, (*slots.get(n * 2 + 1)?)?);
+    let s = line.get(start..end)?;
+    let rest = line.get(sp..)?;
+    if !nocase {
+        return rest.starts_with(s).then_some(s.len());
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < s.len() {
+        match (decode_char(s, i), decode_char(rest, j)) {
+            (Some((c1, len1)), Some((c2, len2))) => {
+                let eq = c1 == c2 || unicode::simple_fold(c1, c1).any(|c| c == c2);
+                if !eq {
+                    return None;
+                }
+                i += len1;
+                j += len2;
+            }
+            _ => {
+                if s[i] != *rest.get(j)? {
+                    return None;
+                }
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    Some(j)
+}
+
+/// lineのsp位置がアサーションの条件を満たすかを判定
+fn check_assert(a: Assertion, line: &[u8], sp: usize) -> bool {
+    match a {
+        Assertion::StartText => sp == 0,
+        Assertion::EndText => sp == line.len(),
+        Assertion::StartLine => sp == 0 || line.get(sp - 1) == Some(&b'\n'),
+        Assertion::EndLine => sp == line.len() || line.get(sp) == Some(&b'\n'),
+        Assertion::WordBoundary => is_word_boundary(line, sp),
+        Assertion::NotWordBoundary => !is_word_boundary(line, sp),
+    }
+}
+
+/// lineのsp位置が単語の境界であるかを判定。
+///
+/// \\wはASCIIの文字のみにマッチするため、前後の1バイトのみを調べればよい。
+fn is_word_boundary(line: &[u8], sp: usize) -> bool {
+    let before = sp.checked_sub(1).and_then(|i| line.get(i));
+    let after = line.get(sp);
+    before.is_some_and(|b| is_word_byte(*b)) != after.is_some_and(|b| is_word_byte(*b))
+}
+
+/// \\wにマッチするバイトであるかを判定
+pub fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+/// 命令列が入力の先頭でのみマッチするかを判定。
+///
+/// ^abcや(^a)bのように、先頭のsave命令を除いた最初の命令が
+/// 入力の先頭を表すアサーションである場合はtrueを返す。
+pub fn is_anchored_start(inst: &[Instruction]) -> bool {
+    inst.iter()
+        .find(|i| !matches!(i, Instruction::Save(_)))
+        .is_some_and(|i| matches!(i, Instruction::Assert(Assertion::StartText)))
+}
+
+/// 深さ優先探索で、マッチを開始する位置を1文字ずつずらしながら探索を行う。
+///
+/// anchoredがtrueの場合は、startからのマッチのみを調べる。
+/// 次に調べる位置は、config.prefilterでマッチが始まりうる位置まで読み飛ばす。
+/// ステップ数の上限は、すべての位置からの探索の合計に対して適用する。
+fn search_depth(
     inst: &[Instruction],
-    line: &[char],
-    mut pc: usize,
-    mut sp: usize,
+    line: &[u8],
+    start: usize,
+    anchored: bool,
+    slots: &mut [Option<usize>],
+    config: &Config,
 ) -> Result<bool, EvalError> {
+    // ある位置から失敗した(pc, sp)は、別の位置から開始した場合も失敗するため共有する
+    let mut visited = Visited::new(inst, line.len(), config.visited_limit)?;
+    let mut backtrack = Backtrack::new(inst, line, config);
+    // 後方参照で参照するグループの範囲は、slotsが短い場合も保存する
+    let mut caps = vec![None; slots.len().max(backref_slots(inst))];
+    let mut sp = start;
     loop {
-        let next = if let Some(i) = inst.get(pc) {
-            i
-        } else {
-            return Err(EvalError::InvalidPC);
+        caps.fill(None);
+        if let Some(s) = caps.get_mut(0) {
+            *s = Some(sp);
+        }
+
+        if let Some(end) = backtrack.run(0, sp, &mut caps, &mut visited)? {
+            slots.copy_from_slice(&caps[..slots.len()]);
+            if let Some(s) = slots.get_mut(1) {
+                *s = Some(end);
+            }
+            return Ok(true);
+        }
+
+        if anchored || sp >= line.len() {
+            return Ok(false);
+        }
+
+        // バイト単位の命令列でも、文字の途中からはマッチを開始しない
+        let len = step_len(line, sp, false);
+        safe_add(&mut sp, &len, || EvalError::SPOverFlow)?;
+        sp = match config.prefilter.next(line, sp) {
+            Some(sp) => sp,
+            None => return Ok(false),
         };
+    }
+}
 
-        match next {
-            Instruction::Char(c) => {
-                if let Some(sp_c) = line.get(sp) {
-                    if c == sp_c {
+/// 深さ優先探索でバックトラックする際に、スタックに積む処理
+enum Job {
+    Explore(usize, usize, usize), // pcとspから探索を再開。3つ目は分岐した時点での経路の長さ
+    Restore(usize, Option<usize>), // スロットを元の値に戻す
+}
+
+/// 深さ優先探索で、再度は訪問しない(pc, sp)の組を記録する
+enum Visited {
+    /// 訪問済みの組をすべて記録するビット集合。widthはspが取りうる値の数で、入力長 + 1
+    All { bits: Vec<u64>, width: usize },
+    /// 現在の経路上の組のみを記録する。後方参照を含む命令列で用いる
+    Path(Vec<(usize, usize)>),
+    /// 訪問済みの組をすべて記録する集合。先読みや後読みの部分命令列の評価で用いる
+    Set(HashSet<(usize, usize)>),
+}
+
+impl Visited {
+    /// 後方参照を含む命令列では、同じ(pc, sp)でもスロットの値によって結果が異なるため、
+    /// 文字を消費せずに同じ命令へ戻るループのみを検出する。
+    ///
+    /// ビット集合は命令数 × (入力長 + 1)ビットとなるため、
+    /// そのバイト数がlimitを超える場合はEvalError::VisitedLimitを返す。
+    fn new(inst: &[Instruction], line_len: usize, limit: usize) -> Result<Self, EvalError> {
+        if backref_slots(inst) > 0 {
+            return Ok(Visited::Path(Vec::new()));
+        }
+
+        let width = line_len + 1;
+        let n = inst.len().checked_mul(width).ok_or(EvalError::SPOverFlow)?;
+        let words = n.div_ceil(64);
+        if words.saturating_mul(8) > limit {
+            return Err(EvalError::VisitedLimit(limit));
+        }
+        Ok(Visited::All {
+            bits: vec![0; words],
+            width,
+        })
+    }
+
+    /// 先読みや後読みの部分命令列の評価に用いる、空の記録を生成。
+    ///
+    /// 部分命令列は訪問する組が少ないことが多いため、ビット集合ではなく集合を用いる。
+    fn sub(inst: &[Instruction]) -> Self {
+        if backref_slots(inst) > 0 {
+            Visited::Path(Vec::new())
+        } else {
+            Visited::Set(HashSet::new())
+        }
+    }
+
+    /// 未訪問の場合は訪問済みとしてtrueを返す。訪問済みの場合はfalseを返す
+    fn insert(&mut self, pc: usize, sp: usize) -> bool {
+        match self {
+            Visited::All { bits, width } => {
+                let i = pc * *width + sp;
+                let (word, bit) = (i / 64, 1 << (i % 64));
+                let fresh = bits[word] & bit == 0;
+                bits[word] |= bit;
+                fresh
+            }
+            Visited::Path(path) => {
+                // 経路上のspは単調増加するため、同じspの組は末尾にのみある
+                let fresh = !path
+                    .iter()
+                    .rev()
+                    .take_while(|(_, s)| *s == sp)
+                    .any(|(p, _)| *p == pc);
+                if fresh {
+                    path.push((pc, sp));
+                }
+                fresh
+            }
+            Visited::Set(set) => set.insert((pc, sp)),
+        }
+    }
+
+    /// 現在の経路の長さ
+    fn path_len(&self) -> usize {
+        match self {
+            Visited::All { .. } | Visited::Set(_) => 0,
+            Visited::Path(path) => path.len(),
+        }
+    }
+
+    /// 分岐した時点の経路に戻す
+    fn backtrack(&mut self, len: usize) {
+        if let Visited::Path(path) = self {
+            path.truncate(len);
+        }
+    }
+}
+
+/// 深さ優先探索の評価器
+struct Backtrack<'a> {
+    inst: &'a [Instruction],
+    line: &'a [u8],
+    config: &'a Config,
+    steps: Steps, // 実行した命令数
+}
+
+impl<'a> Backtrack<'a> {
+    fn new(inst: &'a [Instruction], line: &'a [u8], config: &'a Config) -> Self {
+        Backtrack {
+            inst,
+            line,
+            config,
+            steps: Steps::new(config.step_limit),
+        }
+    }
+
+    /// 深さ優先探索でマッチングを行う。
+    ///
+    /// split命令では2つ目の分岐先をスタックに積んで1つ目の分岐先を先に探索し、
+    /// 失敗した場合にスタックから取り出して探索を再開する。
+    /// save命令では元の値を戻す処理をスタックに積むため、
+    /// 失敗した分岐で保存したスロットは、次の分岐を探索する前に元の値に戻る。
+    ///
+    /// 一度訪問した(pc, sp)からはマッチしないことが分かっているため、再度は訪問しない。
+    /// これにより、(a|a)*bのような正規表現でも計算量はO(命令数 × 入力長)となり、
+    /// (a?)*のような文字を消費しないループも停止する。
+    ///
+    /// pcから評価を開始し、match命令に到達した場合はその位置を返す。
+    /// 実行した命令数やスタックの長さがconfigの上限を超えた場合はErrを返す。
+    fn run(
+        &mut self,
+        pc: usize,
+        sp: usize,
+        slots: &mut [Option<usize>],
+        visited: &mut Visited,
+    ) -> Result<Option<usize>, EvalError> {
+        let (inst, line) = (self.inst, self.line);
+        let limit = self.config.stack_limit;
+        let mut stack = vec![Job::Explore(pc, sp, visited.path_len())];
+
+        while let Some(job) = stack.pop() {
+            let (mut pc, mut sp) = match job {
+                Job::Explore(pc, sp, len) => {
+                    visited.backtrack(len);
+                    (pc, sp)
+                }
+                Job::Restore(n, old) => {
+                    slots[n] = old;
+                    continue;
+                }
+            };
+
+            loop {
+                let next = if let Some(i) = inst.get(pc) {
+                    i
+                } else {
+                    return Err(EvalError::InvalidPC);
+                };
+
+                if !visited.insert(pc, sp) {
+                    break;
+                }
+                self.steps.add(1)?;
+
+                match next {
+                    Instruction::Char(_)
+                    | Instruction::Class(_)
+                    | Instruction::Any(_)
+                    | Instruction::Byte(_, _)
+                    | Instruction::Str(_) => {
+                        if let Some(len) = match_inst(next, line, sp) {
+                            safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                            safe_add(&mut sp, &len, || EvalError::SPOverFlow)?;
+                        } else {
+                            break;
+                        }
+                    }
+                    Instruction::Match(_) => {
+                        return Ok(Some(sp));
+                    }
+                    Instruction::Jump(addr) => {
+                        pc = *addr;
+                    }
+                    Instruction::Split(addr1, addr2) => {
+                        push(
+                            &mut stack,
+                            Job::Explore(*addr2, sp, visited.path_len()),
+                            limit,
+                        )?;
+                        pc = *addr1;
+                    }
+                    Instruction::Save(n) => {
+                        if let Some(old) = slots.get_mut(*n).map(|s| s.replace(sp)) {
+                            push(&mut stack, Job::Restore(*n, old), limit)?;
+                        }
                         safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-                    } else {
-                        return Ok(false);
                     }
-                } else {
-                    return Ok(false);
+                    Instruction::Backref(n, nocase) => {
+                        if let Some(len) = match_backref(line, sp, slots, *n, *nocase) {
+                            safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                            safe_add(&mut sp, &len, || EvalError::SPOverFlow)?;
+                        } else {
+                            break;
+                        }
+                    }
+                    Instruction::Assert(a) => {
+                        if check_assert(*a, line, sp) {
+                            safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                        } else {
+                            break;
+                        }
+                    }
+                    Instruction::Look(look, addr) => {
+                        if self.look(*look, pc, sp, slots, &mut stack)? {
+                            pc = *addr;
+                        } else {
+                            break;
+                        }
+                    }
                 }
             }
-            Instruction::Match => {
-                return Ok(true);
-            }
-            Instruction::Jump(addr) => {
-                pc = *addr;
+        }
+
+        Ok(None)
+    }
+
+    /// pc位置のlook命令が、sp位置で条件を満たすかを判定。
+    ///
+    /// 直後の部分命令列を、先読みではspから、後読みでは読み戻す文字数だけ戻った位置から評価する。
+    /// 部分命令列の評価は独立した探索とするため、訪問済みの(pc, sp)は共有しない。
+    /// 肯定の先読みや後読みが成功した場合は、部分命令列で保存したスロットをslotsに反映し、
+    /// 元の値に戻す処理をstackに積む。
+    fn look(
+        &mut self,
+        look: Look,
+        pc: usize,
+        sp: usize,
+        slots: &mut [Option<usize>],
+        stack: &mut Vec<Job>,
+    ) -> Result<bool, EvalError> {
+        let start = match look {
+            Look::Ahead | Look::NegAhead => Some(sp),
+            Look::Behind(n) | Look::NegBehind(n) => step_back(self.line, sp, n),
+        };
+
+        let mut caps = slots.to_vec();
+        let found = match start {
+            Some(start) => {
+                let mut visited = Visited::sub(self.inst);
+                let mut pc = pc;
+                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                self.run(pc, start, &mut caps, &mut visited)?.is_some()
             }
-            Instruction::Split(addr1, addr2) => {
-                if eval_depth(inst, line, *addr1, sp)? || eval_depth(inst, line, *addr2, sp)? {
-                    return Ok(true);
-                } else {
-                    return Ok(false);
+            None => false, // 読み戻す文字が足りない
+        };
+
+        if found != look.is_positive() {
+            return Ok(false);
+        }
+
+        if found {
+            for (n, (slot, cap)) in slots.iter_mut().zip(caps).enumerate() {
+                if *slot != cap {
+                    push(stack, Job::Restore(n, *slot), self.config.stack_limit)?;
+                    *slot = cap;
                 }
             }
         }
+        Ok(true)
+    }
+}
+
+/// バックトラック用のスタックに積む。スタックの長さがlimitを超える場合はErrを返す
+fn push(stack: &mut Vec<Job>, job: Job, limit: usize) -> Result<(), EvalError> {
+    if stack.len() >= limit {
+        return Err(EvalError::StackLimit(limit));
+    }
+    stack.push(job);
+    Ok(())
+}
+
+/// lineのsp位置からn文字戻った位置を返す。
+///
+/// 不正なUTF-8の部分は1バイトを1文字とみなす。先頭までにn文字ない場合はNoneを返す。
+fn step_back(line: &[u8], mut sp: usize, n: usize) -> Option<usize> {
+    for _ in 0..n {
+        let len = (1..=4.min(sp))
+            .find(|len| decode_char(line, sp - len).is_some_and(|(_, l)| l == *len))
+            .unwrap_or(1);
+        sp = sp.checked_sub(len)?;
     }
+    Some(sp)
 }
 
 fn pop_ctx(
@@ -97,7 +722,7 @@ fn pop_ctx(
 }
 
 /// 幅優先探索で再帰的にマッチングを行う評価器
-fn eval_width(inst: &[Instruction], line: &[char]) -> Result<bool, EvalError> {
+fn eval_width(inst: &[Instruction], line: &[u8]) -> Result<bool, EvalError> {
     let mut ctx = VecDeque::new();
     let mut pc = 0;
     let mut sp = 0;
@@ -110,27 +735,21 @@ fn eval_width(inst: &[Instruction], line: &[char]) -> Result<bool, EvalError> {
         };
 
         match next {
-            Instruction::Char(c) => {
-                if let Some(sp_c) = line.get(sp) {
-                    if c == sp_c {
-                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-                    } else {
-                        if ctx.is_empty() {
-                            return Ok(false);
-                        } else {
-                            pop_ctx(&mut pc, &mut sp, &mut ctx)?;
-                        }
-                    }
+            Instruction::Char(_)
+            | Instruction::Class(_)
+            | Instruction::Any(_)
+            | Instruction::Byte(_, _)
+            | Instruction::Str(_) => {
+                if let Some(len) = match_inst(next, line, sp) {
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    safe_add(&mut sp, &len, || EvalError::SPOverFlow)?;
+                } else if ctx.is_empty() {
+                    return Ok(false);
                 } else {
-                    if ctx.is_empty() {
-                        return Ok(false);
-                    } else {
-                        pop_ctx(&mut pc, &mut sp, &mut ctx)?;
-                    }
+                    pop_ctx(&mut pc, &mut sp, &mut ctx)?;
                 }
             }
-            Instruction::Match => {
+            Instruction::Match(_) => {
                 return Ok(true);
             }
             Instruction::Jump(addr) => {
@@ -141,6 +760,20 @@ fn eval_width(inst: &[Instruction], line: &[char]) -> Result<bool, EvalError> {
                 ctx.push_back((*addr2, sp));
                 continue;
             }
+            Instruction::Save(_) => {
+                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+            }
+            Instruction::Backref(_, _) => return Err(EvalError::UnsupportedBackref),
+            Instruction::Look(_, _) => return Err(EvalError::UnsupportedLook),
+            Instruction::Assert(a) => {
+                if check_assert(*a, line, sp) {
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                } else if ctx.is_empty() {
+                    return Ok(false);
+                } else {
+                    pop_ctx(&mut pc, &mut sp, &mut ctx)?;
+                }
+            }
         }
 
         if !ctx.is_empty() {
@@ -149,3 +782,309 @@ fn eval_width(inst: &[Instruction], line: &[char]) -> Result<bool, EvalError> {
         }
     }
 }
+
+/// Pike VMのスレッド
+#[derive(Debug, Clone)]
+struct Thread {
+    pc: usize,                // 次に実行する命令のアドレス
+    caps: Vec<Option<usize>>, // スロットの値
+}
+
+/// Pike VMのスレッドリスト。
+///
+/// 同じpcを持つスレッドは重複して追加しないため、
+/// リストの長さは高々命令数となる。
+struct Threads {
+    threads: Vec<Thread>, // 追加順、すなわち優先度順に並べたスレッド
+    visited: Vec<bool>,   // pcが訪問済みかどうか
+    pcs: Vec<usize>,      // 訪問済みのpc
+}
+
+impl Threads {
+    fn new(len: usize) -> Self {
+        Threads {
+            threads: Vec::with_capacity(len),
+            visited: vec![false; len],
+            pcs: Vec::with_capacity(len),
+        }
+    }
+
+    /// pcを訪問済みにする。すでに訪問済みの場合はfalseを返す
+    fn visit(&mut self, pc: usize) -> Result<bool, EvalError> {
+        match self.visited.get_mut(pc) {
+            Some(true) => Ok(false),
+            Some(v) => {
+                *v = true;
+                self.pcs.push(pc);
+                Ok(true)
+            }
+            None => Err(EvalError::InvalidPC),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.threads.clear();
+        for pc in self.pcs.drain(..) {
+            self.visited[pc] = false;
+        }
+    }
+}
+
+/// スレッドthから到達可能なスレッドをリストに追加。
+///
+/// jump、split、save、assert命令は文字を消費しないため、ここで辿ってしまい、
+/// リストには文字を消費する命令かmatch命令のみが実行対象として残る。
+/// splitの1つ目の分岐先を先に追加するため、リスト中の順序はスレッドの優先度順となる。
+fn add_thread(
+    inst: &[Instruction],
+    line: &[u8],
+    list: &mut Threads,
+    stack: &mut Vec<Thread>,
+    th: Thread,
+    sp: usize,
+) -> Result<(), EvalError> {
+    stack.push(th);
+    while let Some(mut th) = stack.pop() {
+        if !list.visit(th.pc)? {
+            continue;
+        }
+
+        match inst.get(th.pc) {
+            Some(Instruction::Jump(addr)) => {
+                th.pc = *addr;
+                stack.push(th);
+            }
+            Some(Instruction::Split(addr1, addr2)) => {
+                stack.push(Thread {
+                    pc: *addr2,
+                    caps: th.caps.clone(),
+                });
+                th.pc = *addr1;
+                stack.push(th);
+            }
+            Some(Instruction::Save(n)) => {
+                if let Some(s) = th.caps.get_mut(*n) {
+                    *s = Some(sp);
+                }
+                safe_add(&mut th.pc, &1, || EvalError::PCOverFlow)?;
+                stack.push(th);
+            }
+            Some(Instruction::Assert(a)) => {
+                // 条件を満たさない場合はスレッドを破棄
+                if check_assert(*a, line, sp) {
+                    safe_add(&mut th.pc, &1, || EvalError::PCOverFlow)?;
+                    stack.push(th);
+                }
+            }
+            Some(_) => list.threads.push(th),
+            None => return Err(EvalError::InvalidPC),
+        }
+    }
+
+    Ok(())
+}
+
+/// Pike VMでマッチングを行う評価器。
+///
+/// 深さ優先や幅優先のように分岐ごとに探索するのではなく、
+/// 実行中のすべてのスレッドを入力の1文字（バイト単位の命令列では1バイト）ごとに
+/// 足並みをそろえて進める。
+/// 各位置でのスレッドは重複を除くため高々命令数個となり、
+/// 計算量はO(命令数 × 入力長 × スロット数)となる。
+///
+/// anchoredがfalseの場合は、各位置で新たなスレッドを最も低い優先度で開始することで、
+/// 入力を再度スライスすることなく、任意の位置から始まるマッチを探索する。
+/// マッチが見つかった場合は、それより優先度の低いスレッドを破棄し、
+/// 優先度の高いスレッドがより長いマッチを見つけられるかを調べる。
+/// 実行中のスレッドがない場合は、config.prefilterでマッチが始まりうる位置まで読み飛ばす。
+/// スレッドを進めた回数がconfig.step_limitを超えた場合はErrを返す。
+pub fn eval_pike(
+    inst: &[Instruction],
+    line: &[u8],
+    start: usize,
+    anchored: bool,
+    earliest: bool,
+    slots: &mut [Option<usize>],
+    config: &Config,
+) -> Result<bool, EvalError> {
+    let mut clist = Threads::new(inst.len()); // 現在の位置で実行するスレッド
+    let mut nlist = Threads::new(inst.len()); // 次の位置で実行するスレッド
+    let mut stack = Vec::new();
+    let mut matched = false;
+    let mut sp = start;
+    let bytes = is_byte_program(inst);
+    let mut steps = Steps::new(config.step_limit);
+
+    loop {
+        if !matched && !anchored && clist.threads.is_empty() {
+            sp = match config.prefilter.next(line, sp) {
+                Some(sp) => sp,
+                None => break,
+            };
+        }
+
+        // マッチが見つかるまでは、この位置から始まるスレッドを追加。
+        // バイト単位の命令列では1バイトずつ進めるが、文字の途中からはマッチを開始しない
+        if !matched && (!anchored || sp == start) && is_char_boundary(line, sp) {
+            let mut caps = vec![None; slots.len()];
+            if let Some(s) = caps.get_mut(0) {
+                *s = Some(sp);
+            }
+            add_thread(
+                inst,
+                line,
+                &mut clist,
+                &mut stack,
+                Thread { pc: 0, caps },
+                sp,
+            )?;
+        }
+
+        // 実行中のスレッドがなく、新たなスレッドも開始しない場合は終了
+        if clist.threads.is_empty() && (matched || anchored) {
+            break;
+        }
+        steps.add(clist.threads.len())?;
+
+        let mut next = sp;
+        safe_add(&mut next, &step_len(line, sp, bytes), || {
+            EvalError::SPOverFlow
+        })?;
+
+        for th in clist.threads.iter() {
+            match &inst[th.pc] {
+                i @ (Instruction::Char(_)
+                | Instruction::Class(_)
+                | Instruction::Any(_)
+                | Instruction::Byte(_, _)) => {
+                    if match_inst(i, line, sp).is_some() {
+                        let mut pc = th.pc;
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                        let th = Thread {
+                            pc,
+                            caps: th.caps.clone(),
+                        };
+                        add_thread(inst, line, &mut nlist, &mut stack, th, next)?;
+                    }
+                }
+                Instruction::Match(_) => {
+                    matched = true;
+                    slots.copy_from_slice(&th.caps);
+                    if let Some(s) = slots.get_mut(1) {
+                        *s = Some(sp);
+                    }
+                    if earliest {
+                        return Ok(true);
+                    }
+                    break; // 優先度の低いスレッドは破棄
+                }
+                // 複数の文字を消費するstr命令は、1文字ずつ進めるPike VMでは実行できない
+                Instruction::Str(_) => return Err(EvalError::InvalidPC),
+                Instruction::Backref(_, _) => return Err(EvalError::UnsupportedBackref),
+                Instruction::Look(_, _) => return Err(EvalError::UnsupportedLook),
+                // add_threadで処理済み
+                Instruction::Jump(_)
+                | Instruction::Split(_, _)
+                | Instruction::Save(_)
+                | Instruction::Assert(_) => (),
+            }
+        }
+
+        if sp >= line.len() {
+            break;
+        }
+
+        sp = next;
+        std::mem::swap(&mut clist, &mut nlist);
+        nlist.clear();
+    }
+
+    Ok(matched)
+}
+
+/// Pike VMで、複数の正規表現をまとめた命令列のマッチングを行う。
+///
+/// 入力中のいずれかの位置でi番目の正規表現がマッチした場合、matched[i]をtrueとする。
+/// eval_pikeと異なり、マッチが見つかった後もすべてのスレッドの実行と、
+/// 各位置での新たなスレッドの開始を続けるため、入力を一度走査するだけで
+/// マッチしたすべての正規表現が求まる。
+/// すべての正規表現がマッチした時点で探索を打ち切り、
+/// earliestがtrueの場合は、いずれかの正規表現がマッチした時点で打ち切る。
+/// スレッドを進めた回数がconfig.step_limitを超えた場合はErrを返す。
+pub fn eval_pike_set(
+    inst: &[Instruction],
+    line: &[u8],
+    earliest: bool,
+    matched: &mut [bool],
+    config: &Config,
+) -> Result<(), EvalError> {
+    let mut clist = Threads::new(inst.len()); // 現在の位置で実行するスレッド
+    let mut nlist = Threads::new(inst.len()); // 次の位置で実行するスレッド
+    let mut stack = Vec::new();
+    let mut remaining = matched.iter().filter(|m| !**m).count(); // まだマッチしていない正規表現の数
+    let mut sp = 0;
+    let bytes = is_byte_program(inst);
+    let mut steps = Steps::new(config.step_limit);
+
+    // マッチした正規表現のみを求めるため、スレッドはスロットを持たない
+    let thread = |pc| Thread {
+        pc,
+        caps: Vec::new(),
+    };
+
+    while remaining > 0 {
+        if is_char_boundary(line, sp) {
+            add_thread(inst, line, &mut clist, &mut stack, thread(0), sp)?;
+        }
+        steps.add(clist.threads.len())?;
+
+        let mut next = sp;
+        safe_add(&mut next, &step_len(line, sp, bytes), || {
+            EvalError::SPOverFlow
+        })?;
+
+        for th in clist.threads.iter() {
+            match &inst[th.pc] {
+                i @ (Instruction::Char(_)
+                | Instruction::Class(_)
+                | Instruction::Any(_)
+                | Instruction::Byte(_, _)) => {
+                    if match_inst(i, line, sp).is_some() {
+                        let mut pc = th.pc;
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                        add_thread(inst, line, &mut nlist, &mut stack, thread(pc), next)?;
+                    }
+                }
+                Instruction::Match(id) => {
+                    if let Some(m) = matched.get_mut(*id) {
+                        if !*m {
+                            *m = true;
+                            remaining -= 1;
+                        }
+                    }
+                    if earliest {
+                        return Ok(());
+                    }
+                }
+                Instruction::Str(_) => return Err(EvalError::InvalidPC),
+                Instruction::Backref(_, _) => return Err(EvalError::UnsupportedBackref),
+                Instruction::Look(_, _) => return Err(EvalError::UnsupportedLook),
+                // add_threadで処理済み
+                Instruction::Jump(_)
+                | Instruction::Split(_, _)
+                | Instruction::Save(_)
+                | Instruction::Assert(_) => (),
+            }
+        }
+
+        if sp >= line.len() {
+            break;
+        }
+
+        sp = next;
+        std::mem::swap(&mut clist, &mut nlist);
+        nlist.clear();
+    }
+
+    Ok(())
+}
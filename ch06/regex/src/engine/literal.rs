@@ -0,0 +1,212 @@
+//! ASTからリテラルを抽出し、マッチしえない位置を評価器の実行前に読み飛ばす
+use super::parser::AST;
+
+/// マッチの候補となる位置を、部分文字列の探索で絞り込むためのフィルタ。
+///
+/// ERROR: (a|b)+のように、マッチが必ずERROR: から始まる場合は、
+/// その文字列が現れる位置からのみマッチを試みればよい。
+/// また、x(a|b)yzのように、マッチが必ずyzを含む場合は、
+/// 入力にyzが現れなければ評価器を実行せずにマッチしないと判定できる。
+#[derive(Debug, Default)]
+pub struct Prefilter {
+    prefix: Option<Finder>,   // マッチの先頭に必ず現れる文字列
+    required: Option<Finder>, // マッチ中に必ず現れる文字列
+}
+
+impl Prefilter {
+    /// ASTからフィルタを生成
+    pub fn new(ast: &AST) -> Self {
+        let lits = Literals::new(ast);
+        let finder = |s: String| (!s.is_empty()).then(|| Finder::new(s.into_bytes()));
+        let prefix = finder(lits.prefix);
+        // 先頭の文字列以上に絞り込めない場合は、先頭の文字列のみを用いる
+        let required = if lits.required.len() > prefix.as_ref().map_or(0, |p| p.needle.len()) {
+            finder(lits.required)
+        } else {
+            None
+        };
+        Prefilter { prefix, required }
+    }
+
+    /// lineのstart以降で探索を開始すべき位置を返す。
+    ///
+    /// マッチしえないと判定できた場合はNoneを返す。
+    pub fn start(&self, line: &[u8], start: usize) -> Option<usize> {
+        let text = line.get(start..)?;
+        if let Some(required) = &self.required {
+            required.find(text)?;
+        }
+        self.next(line, start)
+    }
+
+    /// lineのsp以降で、マッチが始まりうる最初の位置を返す。
+    ///
+    /// 先頭の文字列がない場合はspをそのまま返す。
+    pub fn next(&self, line: &[u8], sp: usize) -> Option<usize> {
+        match &self.prefix {
+            Some(prefix) => Some(sp + prefix.find(line.get(sp..)?)?),
+            None => Some(sp),
+        }
+    }
+}
+
+/// Boyer-Moore-Horspool法による部分文字列の探索
+#[derive(Debug)]
+struct Finder {
+    needle: Vec<u8>,
+    skip: [usize; 256], // 末尾のバイトの値ごとに、探索位置をずらす量
+}
+
+impl Finder {
+    fn new(needle: Vec<u8>) -> Self {
+        let mut skip = [needle.len(); 256];
+        let last = needle.len() - 1;
+        for (i, b) in needle[..last].iter().enumerate() {
+            skip[*b as usize] = last - i;
+        }
+        Finder { needle, skip }
+    }
+
+    /// haystack中で最初にneedleが現れる位置を返す
+    fn find(&self, haystack: &[u8]) -> Option<usize> {
+        let n = self.needle.len();
+        let mut pos = 0;
+        while pos + n <= haystack.len() {
+            let window = &haystack[pos..pos + n];
+            if window == self.needle.as_slice() {
+                return Some(pos);
+            }
+            pos += self.skip[window[n - 1] as usize];
+        }
+        None
+    }
+}
+
+/// 正規表現にマッチする文字列が必ず含むリテラル
+#[derive(Debug, Default)]
+struct Literals {
+    exact: Option<String>, // マッチする文字列がただ1つの場合、その文字列
+    prefix: String,        // マッチする文字列の先頭に必ず現れる文字列
+    suffix: String,        // マッチする文字列の末尾に必ず現れる文字列
+    required: String,      // マッチする文字列中に必ず現れる文字列のうち、最も長いもの
+}
+
+impl Literals {
+    /// ASTからリテラルを抽出
+    fn new(ast: &AST) -> Self {
+        match ast {
+            AST::Char(c) => Literals::exact(c.to_string()),
+            // アサーションや先読み、後読みは文字を消費しないため、空文字列とみなす
+            AST::Assert(_) | AST::Look(_, _) => Literals::exact(String::new()),
+            AST::Capture(_, _, e) => Literals::new(e),
+            AST::Seq(v) => v.iter().fold(Literals::exact(String::new()), |acc, e| {
+                acc.concat(Literals::new(e))
+            }),
+            // 1回以上の繰り返しは、1回分のリテラルを必ず含む
+            AST::Plus(e, _) => Literals::new(e).inexact(),
+            AST::Repeat { e, min, .. } if *min > 0 => Literals::new(e).inexact(),
+            AST::Or(e1, e2) => Literals::new(e1).or(Literals::new(e2)),
+            AST::Star(_, _)
+            | AST::Question(_, _)
+            | AST::Repeat { .. }
+            | AST::Class(_, _)
+            | AST::Any(_)
+            | AST::CaseInsensitive(_)
+            | AST::Backref(_) => Literals::default(),
+        }
+    }
+
+    fn exact(s: String) -> Self {
+        Literals {
+            exact: Some(s.clone()),
+            prefix: s.clone(),
+            suffix: s.clone(),
+            required: s,
+        }
+    }
+
+    /// マッチする文字列がただ1つではないものとする
+    fn inexact(self) -> Self {
+        Literals {
+            exact: None,
+            ..self
+        }
+    }
+
+    /// 連続する2つの式のリテラル
+    fn concat(self, other: Literals) -> Self {
+        // 前の式の末尾と、後の式の先頭は連続して現れる
+        let mut middle = self.suffix.clone();
+        middle.push_str(&other.prefix);
+
+        let required = [self.required, other.required, middle]
+            .into_iter()
+            .max_by_key(|s| s.len())
+            .unwrap_or_default();
+
+        match (self.exact, other.exact) {
+            (Some(mut s1), Some(s2)) => {
+                s1.push_str(&s2);
+                Literals::exact(s1)
+            }
+            (Some(s1), None) => Literals {
+                exact: None,
+                prefix: s1 + &other.prefix,
+                suffix: other.suffix,
+                required,
+            },
+            (None, Some(s2)) => Literals {
+                exact: None,
+                prefix: self.prefix,
+                suffix: self.suffix + &s2,
+                required,
+            },
+            (None, None) => Literals {
+                exact: None,
+                prefix: self.prefix,
+                suffix: other.suffix,
+                required,
+            },
+        }
+    }
+
+    /// いずれかの式にマッチする場合のリテラル
+    fn or(self, other: Literals) -> Self {
+        if self.exact.is_some() && self.exact == other.exact {
+            return self;
+        }
+
+        let prefix: String = self
+            .prefix
+            .chars()
+            .zip(other.prefix.chars())
+            .take_while(|(c1, c2)| c1 == c2)
+            .map(|(c, _)| c)
+            .collect();
+        let mut suffix: Vec<char> = self
+            .suffix
+            .chars()
+            .rev()
+            .zip(other.suffix.chars().rev())
+            .take_while(|(c1, c2)| c1 == c2)
+            .map(|(c, _)| c)
+            .collect();
+        suffix.reverse();
+
+        let suffix: String = suffix.into_iter().collect();
+
+        // 共通の先頭と末尾の文字列は、いずれにマッチする場合も必ず現れる
+        let required = if prefix.len() >= suffix.len() {
+            prefix.clone()
+        } else {
+            suffix.clone()
+        };
+
+        Literals {
+            exact: None,
+            prefix,
+            suffix,
+            required,
+        }
+    }
+}
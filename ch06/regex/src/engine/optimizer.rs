@@ -0,0 +1,161 @@
+//! コード生成した命令列の最適化
+use super::Instruction;
+
+/// 命令列を最適化する。
+///
+/// 以下の最適化を行い、最後にアドレスを振り直す。
+///
+/// - jump命令やsplit命令の分岐先がjump命令の場合、その飛び先へ直接分岐させる
+/// - 分岐先が同じsplit命令をjump命令にする
+/// - 先頭から到達できない命令と、直後の命令へのjump命令を取り除く
+/// - merge_charsがtrueの場合、連続するchar命令をstr命令にまとめる
+///
+/// str命令は複数の文字を一度に消費するため、入力の1文字ごとにスレッドを進める
+/// Pike VMやDFAでは扱えない。そのため、merge_charsは深さ優先探索や
+/// 幅優先探索で評価する場合にのみtrueとする。
+pub fn optimize(mut code: Vec<Instruction>, merge_chars: bool) -> Vec<Instruction> {
+    thread_jumps(&mut code);
+
+    let mut removed = unreachable(&code);
+    for (pc, inst) in code.iter().enumerate() {
+        if matches!(inst, Instruction::Jump(addr) if *addr == pc + 1) {
+            removed[pc] = true;
+        }
+    }
+    let code = renumber(code, &removed);
+
+    if merge_chars {
+        merge(code)
+    } else {
+        code
+    }
+}
+
+/// jump命令とsplit命令の分岐先を、jump命令を辿った先のアドレスに置き換える。
+///
+/// 分岐先が同じとなったsplit命令はjump命令にする。
+fn thread_jumps(code: &mut [Instruction]) {
+    let len = code.len();
+    let resolve = |code: &[Instruction], mut addr: usize| {
+        // jump命令のみからなる無限ループで停止しないよう、辿る回数は命令数までとする
+        for _ in 0..len {
+            match code.get(addr) {
+                Some(Instruction::Jump(next)) => addr = *next,
+                _ => break,
+            }
+        }
+        addr
+    };
+
+    for pc in 0..len {
+        let inst = match &code[pc] {
+            Instruction::Jump(addr) => Instruction::Jump(resolve(code, *addr)),
+            Instruction::Split(addr1, addr2) => {
+                let (addr1, addr2) = (resolve(code, *addr1), resolve(code, *addr2));
+                if addr1 == addr2 {
+                    Instruction::Jump(addr1)
+                } else {
+                    Instruction::Split(addr1, addr2)
+                }
+            }
+            _ => continue,
+        };
+        code[pc] = inst;
+    }
+}
+
+/// 先頭から到達できない命令であるかを、命令ごとに求める
+fn unreachable(code: &[Instruction]) -> Vec<bool> {
+    let mut removed = vec![true; code.len()];
+    let mut stack = vec![0];
+    while let Some(pc) = stack.pop() {
+        match removed.get_mut(pc) {
+            Some(r) if *r => *r = false,
+            _ => continue,
+        }
+
+        match &code[pc] {
+            Instruction::Match(_) => (),
+            Instruction::Jump(addr) => stack.push(*addr),
+            Instruction::Split(addr1, addr2) => {
+                stack.push(*addr1);
+                stack.push(*addr2);
+            }
+            // 部分命令列と、その後の命令の両方に到達できる
+            Instruction::Look(_, addr) => {
+                stack.push(pc + 1);
+                stack.push(*addr);
+            }
+            _ => stack.push(pc + 1),
+        }
+    }
+    removed
+}
+
+/// 連続するchar命令をstr命令にまとめる。
+///
+/// 2つ目以降のchar命令が分岐先となっている場合は、そこで区切る。
+fn merge(mut code: Vec<Instruction>) -> Vec<Instruction> {
+    let mut targets = vec![false; code.len()];
+    for inst in code.iter() {
+        match inst {
+            Instruction::Jump(addr) | Instruction::Look(_, addr) => targets[*addr] = true,
+            Instruction::Split(addr1, addr2) => {
+                targets[*addr1] = true;
+                targets[*addr2] = true;
+            }
+            _ => (),
+        }
+    }
+
+    let mut removed = vec![false; code.len()];
+    let mut pc = 0;
+    while pc < code.len() {
+        let mut end = pc;
+        let mut s = String::new();
+        while let Some(Instruction::Char(c)) = code.get(end) {
+            if end > pc && targets[end] {
+                break;
+            }
+            s.push(*c);
+            end += 1;
+        }
+
+        if end - pc >= 2 {
+            code[pc] = Instruction::Str(s);
+            removed[pc + 1..end].fill(true);
+        }
+        pc = end.max(pc + 1);
+    }
+
+    renumber(code, &removed)
+}
+
+/// removedがtrueの命令を取り除き、分岐先のアドレスを振り直す。
+///
+/// 取り除いた命令への分岐は、その次に残る命令への分岐とする。
+fn renumber(code: Vec<Instruction>, removed: &[bool]) -> Vec<Instruction> {
+    // new_addr[pc]は、pc以降で最初に残る命令の新しいアドレス
+    let mut new_addr = Vec::with_capacity(code.len() + 1);
+    let mut n = 0;
+    for r in removed.iter() {
+        new_addr.push(n);
+        if !r {
+            n += 1;
+        }
+    }
+    new_addr.push(n);
+
+    code.into_iter()
+        .zip(removed)
+        .filter(|(_, r)| !**r)
+        .map(|(inst, _)| match inst {
+            Instruction::Jump(addr) => Instruction::Jump(new_addr[addr]),
+            Instruction::Split(addr1, addr2) => {
+                Instruction::Split(new_addr[addr1], new_addr[addr2])
+            }
+            Instruction::Look(look, addr) => Instruction::Look(look, new_addr[addr]),
+            inst => inst,
+        })
+        .collect()
+}
@@ -1,18 +1,31 @@
 //! 正規表現の式をパースし、抽象構文木に変換
+use super::{class::CharClass, unicode, Assertion, Look};
 use std::{
     error::Error,
     fmt::{self, Display},
+    iter::{Enumerate, Peekable},
     mem::take,
+    str::Chars,
 };
 
 /// パースエラーを表すための型
 #[derive(Debug)]
 pub enum ParseError {
-    InvalidEscape(usize, char), // 誤ったエスケープシーケンス
-    InvalidRightParen(usize),   // 左開き括弧無し
-    NoPrev(usize),              // +、|、*、?の前に式がない
-    NoRightParen,               // 右閉じ括弧無し
-    Empty,                      // 空のパターン
+    InvalidEscape(usize, char),      // 誤ったエスケープシーケンス
+    InvalidRightParen(usize),        // 左開き括弧無し
+    NoPrev(usize),                   // +、|、*、?の前に式がない
+    NoRightParen,                    // 右閉じ括弧無し
+    NoRightBracket,                  // 文字クラスの閉じ括弧無し
+    InvalidRange(usize, char, char), // 文字クラスの範囲の始点が終点より大きい
+    InvalidRepeat(usize),            // {n,m}の形式が誤っているか、nがmより大きい
+    TooLargeRepeat(usize),           // {n,m}の繰り返し回数がMAX_REPEATより大きい
+    InvalidHexEscape(usize),         // \\x41や\\u{3042}の形式が誤っているか、文字として不正
+    InvalidFlag(usize),              // (?i)のようなフラグの指定が誤っている
+    InvalidBackref(usize, usize),    // 存在しないグループへの後方参照
+    InvalidLookbehind(usize),        // 後読みの式の長さが一定でない
+    InvalidProperty(usize),          // \\p{L}の形式が誤っているか、存在しないプロパティ
+    InvalidGroupName(usize),         // グループの名前の形式が誤っているか、名前が重複している
+    Empty,                           // 空のパターン
 }
 
 /// パースエラーを表示するために、Displayトレイトを実装
@@ -31,6 +44,48 @@ impl Display for ParseError {
             ParseError::NoRightParen => {
                 write!(f, "ParseError: no right parenthesis")
             }
+            ParseError::InvalidRepeat(pos) => {
+                write!(f, "ParseError: invalid repetition: pos = {pos}")
+            }
+            ParseError::TooLargeRepeat(pos) => {
+                write!(
+                    f,
+                    "ParseError: repetition count exceeds {MAX_REPEAT}: pos = {pos}"
+                )
+            }
+            ParseError::InvalidHexEscape(pos) => {
+                write!(f, "ParseError: invalid hex escape: pos = {pos}")
+            }
+            ParseError::InvalidGroupName(pos) => {
+                write!(f, "ParseError: invalid group name: pos = {pos}")
+            }
+            ParseError::InvalidProperty(pos) => {
+                write!(f, "ParseError: invalid Unicode property: pos = {pos}")
+            }
+            ParseError::InvalidFlag(pos) => {
+                write!(f, "ParseError: invalid flag: pos = {pos}")
+            }
+            ParseError::InvalidLookbehind(pos) => {
+                write!(
+                    f,
+                    "ParseError: lookbehind must have a fixed length: pos = {pos}"
+                )
+            }
+            ParseError::InvalidBackref(pos, n) => {
+                write!(
+                    f,
+                    "ParseError: invalid backreference: pos = {pos}, group = {n}"
+                )
+            }
+            ParseError::NoRightBracket => {
+                write!(f, "ParseError: no right bracket")
+            }
+            ParseError::InvalidRange(pos, start, end) => {
+                write!(
+                    f,
+                    "ParseError: invalid range: pos = {pos}, range = '{start}-{end}'"
+                )
+            }
             ParseError::Empty => write!(f, "ParseError: empty expression"),
         }
     }
@@ -38,26 +93,75 @@ impl Display for ParseError {
 
 impl Error for ParseError {} // エラー用に、Errorトレイトを実装
 
+/// {n,m}で指定できる繰り返し回数の上限
+pub const MAX_REPEAT: usize = 1000;
+
 /// 抽象構文木を表現するための型
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub enum AST {
     Char(char),
-    Plus(Box<AST>),
-    Star(Box<AST>),
-    Question(Box<AST>),
+    Plus(Box<AST>, bool),     // boolがfalseの場合は、e+?のように非貪欲
+    Star(Box<AST>, bool),     // boolがfalseの場合は、e*?のように非貪欲
+    Question(Box<AST>, bool), // boolがfalseの場合は、e??のように非貪欲
     Or(Box<AST>, Box<AST>),
     Seq(Vec<AST>),
+    // 括弧で囲まれたグループ。1から始まるグループ番号と、名前付きグループの場合は名前を持つ
+    Capture(usize, Option<String>, Box<AST>),
+    Class(CharClass, bool), // [a-z]のような文字クラス。trueの場合は[^a-z]のように否定
+    Any(bool),              // 任意の1文字。trueの場合は改行にもマッチ
+    Assert(Assertion),      // ^や$のような、文字を消費しない位置の条件
+    CaseInsensitive(Box<AST>), // 大文字と小文字を区別しない後方参照
+    Backref(usize),         // \\1のような後方参照。参照するグループ番号を持つ
+    Look(Look, Box<AST>),   // (?=e)のような先読み、もしくは(?<=e)のような後読み
+    Repeat {
+        // {n}、{n,}、{n,m}による繰り返し。maxがNoneの場合は上限なし
+        e: Box<AST>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
 }
 
+/// パース中の入力。文字とその位置を先読みしながら読み進める
+type Input<'a> = Peekable<Enumerate<Chars<'a>>>;
+
 /// parse_plus_star_question関数で利用するための列挙型
+#[allow(clippy::upper_case_acronyms)]
 enum PSQ {
     Plus,
     Star,
     Question,
+    Repeat(usize, Option<usize>),
+}
+
+/// 括弧で囲まれた部分の種類
+enum Group {
+    Capture(usize, Option<String>), // グループ。グループ番号と、名前付きの場合は名前を持つ
+    NonCapture,                     // (?:e)のような、番号を持たないグループ
+    Look {
+        // 先読みもしくは後読み。posは開き括弧の位置
+        ahead: bool,
+        negated: bool,
+        pos: usize,
+    },
+}
+
+/// パース時のオプション
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+    pub multi_line: bool,       // trueの場合、^と$は行頭と行末にマッチ
+    pub dot_nl: bool,           // trueの場合、.は改行にもマッチ
+    pub case_insensitive: bool, // trueの場合、大文字と小文字を区別しない
 }
 
 /// 正規表現を抽象構文木に変換
 pub fn parse(expr: &str) -> Result<AST, ParseError> {
+    parse_with_flags(expr, Flags::default())
+}
+
+/// オプションを指定して、正規表現を抽象構文木に変換
+pub fn parse_with_flags(expr: &str, mut flags: Flags) -> Result<AST, ParseError> {
     // 内部状態を表現するための型
     // Char状態 : 文字列処理中
     // Escape状態 : エスケープシーケンス処理中
@@ -70,37 +174,101 @@ pub fn parse(expr: &str) -> Result<AST, ParseError> {
     let mut seq_or = Vec::new(); // 現在のOrのコンテキスト
     let mut stack = Vec::new(); // コンテキストのスタック
     let mut state = ParseState::Char; // 現在の状態
+    let mut ncap = 0; // これまでに現れたグループの数
+    let mut names = Vec::new(); // これまでに現れたグループの名前
 
-    for (i, c) in expr.chars().enumerate() {
+    let mut chars = expr.chars().enumerate().peekable();
+    while let Some((i, c)) = chars.next() {
         match &state {
             ParseState::Char => {
                 match c {
-                    '+' => parse_plus_star_question(&mut seq, PSQ::Plus, i)?,
-                    '*' => parse_plus_star_question(&mut seq, PSQ::Star, i)?,
-                    '?' => parse_plus_star_question(&mut seq, PSQ::Question, i)?,
+                    '+' => parse_plus_star_question(&mut seq, &mut chars, PSQ::Plus, i)?,
+                    '*' => parse_plus_star_question(&mut seq, &mut chars, PSQ::Star, i)?,
+                    '?' => parse_plus_star_question(&mut seq, &mut chars, PSQ::Question, i)?,
+                    '{' => {
+                        let (min, max) = parse_repeat(&mut chars, i)?;
+                        let psq = PSQ::Repeat(min, max);
+                        parse_plus_star_question(&mut seq, &mut chars, psq, i)?
+                    }
                     '(' => {
+                        let prev_flags = flags; // 閉じ括弧で元に戻すフラグ
+                        let group = if chars.next_if(|(_, c)| *c == '?').is_some() {
+                            match parse_group(&mut chars, i)? {
+                                Some(group) => group,
+                                // (?i)のようなフラグの指定は、現在のグループの終わりまで有効
+                                // (?i:e)のように指定した場合は、eにのみ有効
+                                None if parse_flags(&mut chars, &mut flags, i)? => {
+                                    Group::NonCapture
+                                }
+                                None => continue,
+                            }
+                        } else {
+                            Group::Capture(0, None)
+                        };
+
+                        let group = match group {
+                            Group::Capture(_, name) => {
+                                if let Some(name) = &name {
+                                    if names.contains(name) {
+                                        return Err(ParseError::InvalidGroupName(i));
+                                    }
+                                    names.push(name.clone());
+                                }
+
+                                // グループ番号は開き括弧の出現順に割り当てる
+                                ncap += 1;
+                                Group::Capture(ncap, name)
+                            }
+                            group => group,
+                        };
+
                         // 現在のコンテキストをスタックに追加し、
                         // 現在のコンテキストを空の状態にする
                         let prev = take(&mut seq);
                         let prev_or = take(&mut seq_or);
-                        stack.push((prev, prev_or));
+                        stack.push((prev, prev_or, group, prev_flags));
                     }
                     ')' => {
                         // 現在のコンテキストをスタックからポップ
-                        if let Some((mut prev, prev_or)) = stack.pop() {
+                        if let Some((mut prev, prev_or, group, prev_flags)) = stack.pop() {
                             // "()"のように式が空の場合はpushしない
                             if !seq.is_empty() {
                                 seq_or.push(AST::Seq(seq));
                             }
 
-                            // Orを生成
-                            if let Some(ast) = fold_or(seq_or) {
-                                prev.push(ast);
-                            }
+                            // Orを生成し、グループとして追加
+                            // "()"のように式が空の場合も、空文字列にマッチするグループとする
+                            let ast = fold_or(seq_or).unwrap_or(AST::Seq(Vec::new()));
+                            let ast = match group {
+                                Group::Capture(n, name) => AST::Capture(n, name, Box::new(ast)),
+                                Group::NonCapture => ast,
+                                Group::Look {
+                                    ahead,
+                                    negated,
+                                    pos,
+                                } => {
+                                    let look = match (ahead, negated) {
+                                        (true, false) => Look::Ahead,
+                                        (true, true) => Look::NegAhead,
+                                        (false, _) => {
+                                            let n = fixed_len(&ast)
+                                                .ok_or(ParseError::InvalidLookbehind(pos))?;
+                                            if negated {
+                                                Look::NegBehind(n)
+                                            } else {
+                                                Look::Behind(n)
+                                            }
+                                        }
+                                    };
+                                    AST::Look(look, Box::new(ast))
+                                }
+                            };
+                            prev.push(ast);
 
                             // 以前のコンテキストを、現在のコンテキストにする
                             seq = prev;
                             seq_or = prev_or;
+                            flags = prev_flags;
                         } else {
                             // "abc)"のように、開き括弧がないのに閉じ括弧がある場合はエラー
                             return Err(ParseError::InvalidRightParen(i));
@@ -115,14 +283,26 @@ pub fn parse(expr: &str) -> Result<AST, ParseError> {
                             seq_or.push(AST::Seq(prev));
                         }
                     }
+                    '[' => seq.push(ignore_case(parse_class(&mut chars)?, flags)),
+                    '.' => seq.push(AST::Any(flags.dot_nl)),
+                    '^' => seq.push(AST::Assert(if flags.multi_line {
+                        Assertion::StartLine
+                    } else {
+                        Assertion::StartText
+                    })),
+                    '$' => seq.push(AST::Assert(if flags.multi_line {
+                        Assertion::EndLine
+                    } else {
+                        Assertion::EndText
+                    })),
                     '\\' => state = ParseState::Escape,
-                    _ => seq.push(AST::Char(c)),
+                    _ => seq.push(ignore_case(AST::Char(c), flags)),
                 };
             }
             ParseState::Escape => {
                 // エスケープシーケンス処理
-                let ast = parse_escape(i, c)?;
-                seq.push(ast);
+                let ast = parse_escape(i, c, &mut chars, ncap)?;
+                seq.push(ignore_case(ast, flags));
                 state = ParseState::Char;
             }
         }
@@ -149,18 +329,27 @@ pub fn parse(expr: &str) -> Result<AST, ParseError> {
 /// +、*、?をASTに変換
 ///
 /// 後置記法で、+、*、?の前にパターンがない場合はエラー
+/// 直後に?が続く場合は、+?、*?、??のような非貪欲な限量子とする
 ///
 /// 例 : *ab、abc|+などはエラー
 fn parse_plus_star_question(
     seq: &mut Vec<AST>,
+    chars: &mut Input,
     ast_type: PSQ,
     pos: usize,
 ) -> Result<(), ParseError> {
     if let Some(prev) = seq.pop() {
+        let greedy = chars.next_if(|(_, c)| *c == '?').is_none();
         let ast = match ast_type {
-            PSQ::Plus => AST::Plus(Box::new(prev)),
-            PSQ::Star => AST::Star(Box::new(prev)),
-            PSQ::Question => AST::Question(Box::new(prev)),
+            PSQ::Plus => AST::Plus(Box::new(prev), greedy),
+            PSQ::Star => AST::Star(Box::new(prev), greedy),
+            PSQ::Question => AST::Question(Box::new(prev), greedy),
+            PSQ::Repeat(min, max) => AST::Repeat {
+                e: Box::new(prev),
+                min,
+                max,
+                greedy,
+            },
         };
         seq.push(ast);
         Ok(())
@@ -169,15 +358,408 @@ fn parse_plus_star_question(
     }
 }
 
-/// 特殊文字のエスケープ
-fn parse_escape(pos: usize, c: char) -> Result<AST, ParseError> {
-    match c {
-        '\\' | '(' | ')' | '|' | '+' | '*' | '?' => Ok(AST::Char(c)),
-        _ => {
-            let err = ParseError::InvalidEscape(pos, c);
-            Err(err)
+/// ASTに含まれるグループの名前を、グループ番号順に返す。
+///
+/// 0番目はマッチ全体を表す。マッチ全体と、名前のないグループの名前はNoneとなる。
+/// 返り値の長さは、マッチ全体を含めたグループの数となる。
+pub fn capture_names(ast: &AST) -> Vec<Option<String>> {
+    fn collect(ast: &AST, names: &mut Vec<Option<String>>) {
+        match ast {
+            AST::Char(_) | AST::Class(_, _) | AST::Any(_) | AST::Assert(_) | AST::Backref(_) => (),
+            AST::Plus(e, _) | AST::Star(e, _) | AST::Question(e, _) => collect(e, names),
+            AST::CaseInsensitive(e) | AST::Look(_, e) | AST::Repeat { e, .. } => collect(e, names),
+            AST::Or(e1, e2) => {
+                collect(e1, names);
+                collect(e2, names);
+            }
+            AST::Seq(v) => v.iter().for_each(|e| collect(e, names)),
+            AST::Capture(n, name, e) => {
+                if names.len() <= *n {
+                    names.resize(*n + 1, None);
+                }
+                names[*n] = name.clone();
+                collect(e, names);
+            }
+        }
+    }
+
+    let mut names = vec![None];
+    collect(ast, &mut names);
+    names
+}
+
+/// (?の直後から、グループの種類を表す部分をパース。
+///
+/// 以下の種類に対応する。
+///
+/// - (?:e) : 番号を持たないグループ
+/// - (?P<name>e)、(?<name>e) : 名前付きグループ。グループ番号は呼び出し元で割り当てる
+/// - (?=e)、(?!e)、(?<=e)、(?<!e) : 先読みと後読み
+///
+/// いずれでもない場合は、(?i)のようなフラグの指定とみなし、読み進めずにNoneを返す。
+fn parse_group(chars: &mut Input, pos: usize) -> Result<Option<Group>, ParseError> {
+    if chars.next_if(|(_, c)| *c == ':').is_some() {
+        return Ok(Some(Group::NonCapture));
+    }
+
+    if chars.next_if(|(_, c)| *c == 'P').is_some() {
+        if chars.next_if(|(_, c)| *c == '<').is_none() {
+            return Err(ParseError::InvalidGroupName(pos));
         }
+        return Ok(Some(Group::Capture(0, Some(parse_name(chars, pos)?))));
     }
+
+    let ahead = chars.next_if(|(_, c)| *c == '<').is_none();
+    let negated = match chars.next_if(|(_, c)| *c == '=' || *c == '!') {
+        Some((_, c)) => c == '!',
+        // (?<の後に=か!が続かない場合は名前付きグループ
+        None if !ahead => return Ok(Some(Group::Capture(0, Some(parse_name(chars, pos)?)))),
+        None => return Ok(None),
+    };
+    Ok(Some(Group::Look {
+        ahead,
+        negated,
+        pos,
+    }))
+}
+
+/// 名前付きグループの名前を、<の直後から>までを読み進めてパース。
+///
+/// 名前は1文字以上の英数字とアンダースコアからなり、数字で始まらないものとする。
+fn parse_name(chars: &mut Input, pos: usize) -> Result<String, ParseError> {
+    let mut name = String::new();
+    while let Some((_, c)) = chars.next_if(|(_, c)| c.is_alphanumeric() || *c == '_') {
+        name.push(c);
+    }
+
+    let valid = name.chars().next().is_some_and(|c| !c.is_numeric());
+    if !valid || chars.next_if(|(_, c)| *c == '>').is_none() {
+        return Err(ParseError::InvalidGroupName(pos));
+    }
+    Ok(name)
+}
+
+/// 式にマッチする文字列の文字数が一定であれば、その文字数を返す
+fn fixed_len(ast: &AST) -> Option<usize> {
+    match ast {
+        AST::Char(_) | AST::Class(_, _) | AST::Any(_) => Some(1),
+        AST::Assert(_) | AST::Look(_, _) => Some(0),
+        AST::CaseInsensitive(e) | AST::Capture(_, _, e) => fixed_len(e),
+        AST::Seq(v) => v.iter().map(fixed_len).sum(),
+        AST::Or(e1, e2) => {
+            let n = fixed_len(e1)?;
+            (fixed_len(e2)? == n).then_some(n)
+        }
+        AST::Repeat {
+            e,
+            min,
+            max: Some(max),
+            ..
+        } if min == max => fixed_len(e)?.checked_mul(*min),
+        // 空文字列にのみマッチする式の繰り返しは、空文字列にのみマッチする
+        AST::Plus(e, _) | AST::Star(e, _) | AST::Question(e, _) | AST::Repeat { e, .. } => {
+            (fixed_len(e)? == 0).then_some(0)
+        }
+        AST::Backref(_) => None,
+    }
+}
+
+/// (?i)、(?m)、(?s)のようなフラグの指定をパースし、flagsに反映。
+///
+/// (?i:e)のように:で終わる場合はOk(true)を、)で終わる場合はOk(false)を返す。
+///
+/// (?の直後から、閉じ括弧までを読み進める。
+/// (?im)のように複数指定でき、(?-i)や(?m-s)のように-の後のフラグは無効にする。
+///
+/// - i : 大文字と小文字を区別しない
+/// - m : ^と$を行頭と行末にもマッチさせる
+/// - s : .を改行にもマッチさせる
+fn parse_flags(chars: &mut Input, flags: &mut Flags, pos: usize) -> Result<bool, ParseError> {
+    let mut enable = true;
+    let mut n = 0; // 直前の-以降に指定されたフラグの数
+    loop {
+        let (_, c) = chars.next().ok_or(ParseError::NoRightParen)?;
+        match c {
+            'i' => flags.case_insensitive = enable,
+            'm' => flags.multi_line = enable,
+            's' => flags.dot_nl = enable,
+            '-' if enable => {
+                enable = false;
+                n = 0;
+                continue;
+            }
+            ')' if n > 0 => return Ok(false),
+            ':' if n > 0 => return Ok(true),
+            _ => return Err(ParseError::InvalidFlag(pos)),
+        }
+        n += 1;
+    }
+}
+
+/// 大文字と小文字を区別しない場合に、ASTを変換。
+///
+/// 文字と文字クラスは、大文字と小文字を同一視した文字を加えた文字クラスとする。
+/// たとえば、aは[Aa]に、[^k]は[^KkK]となり、äは[Ää]となる。
+/// 大文字と小文字の区別が無い文字の場合は、そのままの文字とする。
+/// 変換はパース時に一度だけ行うため、{n}で繰り返す場合も変換した文字クラスを複製するのみとなる。
+///
+/// 後方参照は、ASTをAST::CaseInsensitiveで囲む。
+fn ignore_case(ast: AST, flags: Flags) -> AST {
+    if !flags.case_insensitive {
+        return ast;
+    }
+
+    match ast {
+        AST::Char(c) => {
+            let cls = CharClass::new(vec![(c, c)]).case_fold();
+            if cls.ranges() == [(c, c)] {
+                AST::Char(c)
+            } else {
+                AST::Class(cls, false)
+            }
+        }
+        // 否定された文字クラスは、否定する前の文字クラスを変換した後で否定する
+        AST::Class(cls, negated) => AST::Class(cls.case_fold(), negated),
+        AST::Backref(_) => AST::CaseInsensitive(Box::new(ast)),
+        ast => ast,
+    }
+}
+
+/// {n}、{n,}、{n,m}の形式の繰り返し回数をパース。
+///
+/// 開き括弧の直後から、対応する閉じ括弧までを読み進め、(n, m)を返す。
+/// {n,}の場合、mはNoneとなる。
+/// nかmがMAX_REPEATより大きい場合はエラーとなる。
+fn parse_repeat(chars: &mut Input, pos: usize) -> Result<(usize, Option<usize>), ParseError> {
+    let min = parse_number(chars).ok_or(ParseError::InvalidRepeat(pos))?;
+    let max = if chars.next_if(|(_, c)| *c == ',').is_some() {
+        if chars.peek().is_some_and(|(_, c)| *c == '}') {
+            None
+        } else {
+            Some(parse_number(chars).ok_or(ParseError::InvalidRepeat(pos))?)
+        }
+    } else {
+        Some(min)
+    };
+
+    if chars.next_if(|(_, c)| *c == '}').is_none() || max.is_some_and(|max| min > max) {
+        return Err(ParseError::InvalidRepeat(pos));
+    }
+
+    if max.unwrap_or(min) > MAX_REPEAT {
+        return Err(ParseError::TooLargeRepeat(pos));
+    }
+
+    Ok((min, max))
+}
+
+/// 10進数の数値をパース。数字が無い場合やオーバーフローする場合はNoneを返す
+fn parse_number(chars: &mut Input) -> Option<usize> {
+    let mut n: Option<usize> = None;
+    while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+        let d = c.to_digit(10)? as usize;
+        n = Some(n.unwrap_or(0).checked_mul(10)?.checked_add(d)?);
+    }
+    n
+}
+
+/// [a-z0-9_]や[^abc]のような文字クラスをASTに変換。
+///
+/// 開き括弧の直後から、対応する閉じ括弧までを読み進める。
+/// 先頭の]と、先頭もしくは末尾の-は通常の文字として扱う。
+/// [\\d_]のように、エスケープによる文字クラスを含めることもできる。
+fn parse_class(chars: &mut Input) -> Result<AST, ParseError> {
+    let negated = chars.next_if(|(_, c)| *c == '^').is_some();
+    let mut ranges = Vec::new();
+    let mut first = true;
+
+    loop {
+        let (pos, c) = chars.next().ok_or(ParseError::NoRightBracket)?;
+        let start = match c {
+            ']' if !first => return Ok(AST::Class(CharClass::new(ranges), negated)),
+            '\\' => match parse_class_escape(chars)? {
+                Escape::Char(c) => c,
+                Escape::Class(cls, negated) => {
+                    // 文字クラスは範囲の始点とならないため、そのまま追加
+                    push_class(&mut ranges, cls, negated);
+                    first = false;
+                    continue;
+                }
+            },
+            _ => c,
+        };
+        first = false;
+
+        // 範囲指定でなければ1文字のみ追加
+        if chars.next_if(|(_, c)| *c == '-').is_none() {
+            ranges.push((start, start));
+            continue;
+        }
+
+        let end = match chars.next().ok_or(ParseError::NoRightBracket)? {
+            (_, ']') => {
+                // [a-]のように末尾に-がある場合は、-を文字として扱う
+                ranges.push((start, start));
+                ranges.push(('-', '-'));
+                return Ok(AST::Class(CharClass::new(ranges), negated));
+            }
+            (_, '\\') => match parse_class_escape(chars)? {
+                Escape::Char(c) => c,
+                Escape::Class(cls, negated) => {
+                    // [a-\\d]のように終点が文字クラスの場合は、-を文字として扱う
+                    ranges.push((start, start));
+                    ranges.push(('-', '-'));
+                    push_class(&mut ranges, cls, negated);
+                    continue;
+                }
+            },
+            (_, c) => c,
+        };
+
+        if start > end {
+            return Err(ParseError::InvalidRange(pos, start, end));
+        }
+        ranges.push((start, end));
+    }
+}
+
+/// 文字クラス中のエスケープ
+fn parse_class_escape(chars: &mut Input) -> Result<Escape, ParseError> {
+    let (pos, c) = chars.next().ok_or(ParseError::NoRightBracket)?;
+    parse_escape_seq(pos, c, chars)
+}
+
+/// 文字クラスの範囲をrangesに追加。negatedがtrueの場合は補集合を追加
+fn push_class(ranges: &mut Vec<(char, char)>, cls: CharClass, negated: bool) {
+    let cls = if negated { cls.negate() } else { cls };
+    ranges.extend_from_slice(cls.ranges());
+}
+
+/// エスケープシーケンスをASTに変換
+///
+/// \\1から\\9は後方参照とする。参照できるのは、それまでに開き括弧が現れたグループのみで、
+/// ncapはそれまでに現れたグループの数。
+/// \\bと\\Bは単語の境界、\\Aと\\zは入力の先頭と末尾のアサーションとする。
+/// これらはフラグによらず同じ意味となる。
+fn parse_escape(pos: usize, c: char, chars: &mut Input, ncap: usize) -> Result<AST, ParseError> {
+    if let Some(n) = c.to_digit(10).filter(|n| *n > 0) {
+        let n = n as usize;
+        if n > ncap {
+            return Err(ParseError::InvalidBackref(pos, n));
+        }
+        return Ok(AST::Backref(n));
+    }
+
+    let a = match c {
+        'b' => Some(Assertion::WordBoundary),
+        'B' => Some(Assertion::NotWordBoundary),
+        'A' => Some(Assertion::StartText),
+        'z' => Some(Assertion::EndText),
+        _ => None,
+    };
+    if let Some(a) = a {
+        return Ok(AST::Assert(a));
+    }
+
+    match parse_escape_seq(pos, c, chars)? {
+        Escape::Char(c) => Ok(AST::Char(c)),
+        Escape::Class(cls, negated) => Ok(AST::Class(cls, negated)),
+    }
+}
+
+/// エスケープシーケンスが表すもの
+enum Escape {
+    Char(char),             // 1文字
+    Class(CharClass, bool), // \\dのような文字クラス。trueの場合は\\Dのように否定
+}
+
+/// バックスラッシュの直後の文字cから始まるエスケープシーケンスをパース。
+///
+/// 以下のエスケープシーケンスに対応する。
+///
+/// - \\d、\\w、\\s : 数字、単語を構成する文字、空白文字
+/// - \\D、\\W、\\S : 上記の否定
+/// - \\n、\\t、\\r、\\0 : 改行、タブ、復帰、ヌル文字
+/// - \\x41、\\x{3042} : 16進数による文字コードの指定
+/// - \\u{1F642} : 16進数によるUnicodeのコードポイントの指定
+/// - \\p{L}、\\p{Han}、\\pL : Unicodeの一般カテゴリもしくは用字に属する文字
+/// - \\P{L}、\\P{Han}、\\PL : 上記の否定
+/// - 特殊文字のエスケープ
+fn parse_escape_seq(pos: usize, c: char, chars: &mut Input) -> Result<Escape, ParseError> {
+    let e = match c {
+        'd' => Escape::Class(CharClass::digit(), false),
+        'D' => Escape::Class(CharClass::digit(), true),
+        'w' => Escape::Class(CharClass::word(), false),
+        'W' => Escape::Class(CharClass::word(), true),
+        's' => Escape::Class(CharClass::space(), false),
+        'S' => Escape::Class(CharClass::space(), true),
+        'n' => Escape::Char('\n'),
+        't' => Escape::Char('\t'),
+        'r' => Escape::Char('\r'),
+        '0' => Escape::Char('\0'),
+        'x' => Escape::Char(parse_hex(chars, pos, Some(2))?),
+        'u' => Escape::Char(parse_hex(chars, pos, None)?),
+        'p' | 'P' => Escape::Class(parse_property(chars, pos)?, c == 'P'),
+        _ if is_meta(c) || c == '-' => Escape::Char(c),
+        _ => return Err(ParseError::InvalidEscape(pos, c)),
+    };
+    Ok(e)
+}
+
+/// 16進数で表された文字コードをパース。
+///
+/// {3042}のように波括弧で囲まれた1桁から6桁の16進数か、
+/// digitsがSomeの場合は、波括弧で囲まずにちょうどその桁数の16進数を受け付ける。
+fn parse_hex(chars: &mut Input, pos: usize, digits: Option<usize>) -> Result<char, ParseError> {
+    let err = || ParseError::InvalidHexEscape(pos);
+    let mut code: u32 = 0;
+
+    if chars.next_if(|(_, c)| *c == '{').is_some() {
+        let mut n = 0;
+        while let Some((_, c)) = chars.next_if(|(_, c)| *c != '}') {
+            code = code * 16 + c.to_digit(16).ok_or_else(err)?;
+            n += 1;
+            if n > 6 {
+                return Err(err());
+            }
+        }
+        if chars.next().is_none() || n == 0 {
+            return Err(err());
+        }
+    } else {
+        for _ in 0..digits.ok_or_else(err)? {
+            let (_, c) = chars.next().ok_or_else(err)?;
+            code = code * 16 + c.to_digit(16).ok_or_else(err)?;
+        }
+    }
+
+    char::from_u32(code).ok_or_else(err)
+}
+
+/// \\pの直後から、{Han}のように波括弧で囲まれたプロパティ名か、
+/// Lのような1文字のプロパティ名を読み進め、対応する文字クラスを返す
+fn parse_property(chars: &mut Input, pos: usize) -> Result<CharClass, ParseError> {
+    let err = || ParseError::InvalidProperty(pos);
+    let name: String = if chars.next_if(|(_, c)| *c == '{').is_some() {
+        let mut name = String::new();
+        while let Some((_, c)) = chars.next_if(|(_, c)| *c != '}') {
+            name.push(c);
+        }
+        chars.next().ok_or_else(err)?;
+        name
+    } else {
+        chars.next().ok_or_else(err)?.1.to_string()
+    };
+
+    unicode::property(&name).ok_or_else(err)
+}
+
+/// エスケープが必要な特殊文字かを判定
+fn is_meta(c: char) -> bool {
+    matches!(
+        c,
+        '\\' | '(' | ')' | '|' | '+' | '*' | '?' | '[' | ']' | '{' | '}' | '.' | '^' | '$'
+    )
 }
 
 /// orで結合された複数の式をASTに変換
@@ -0,0 +1,931 @@
+//! コンパイル済みの正規表現
+use super::{
+    bytes,
+    codegen::{self, CodeGenError},
+    dfa::{self, Dfa},
+    evaluator::{self, Config, EvalError, EvalMode},
+    literal::Prefilter,
+    optimizer,
+    parser::{self, Flags},
+    utf8, Instruction,
+};
+use crate::helper::DynError;
+use std::{
+    borrow::Cow,
+    fmt::{self, Display},
+    ops::{Index, Range},
+    sync::Arc,
+};
+
+/// 各グループの名前。0番目はマッチ全体を表し、常にNoneとなる
+pub(super) type CaptureNames = Arc<[Option<String>]>;
+
+/// コンパイル済みの正規表現。
+///
+/// パースとコード生成は生成時に一度だけ行い、
+/// 以降のマッチングでは生成済みの命令列を使い回す。
+/// 標準ではマッチングにPike VMを用いるため、どのような正規表現であっても
+/// O(命令数 × 入力長)の時間でマッチングを行う。
+/// EvalMode::Dfaを指定した場合は、構築したDFAの状態をマッチングの間で共有する。
+#[derive(Debug)]
+pub struct Regex {
+    expr: String,
+    code: Vec<Instruction>,
+    names: CaptureNames, // 各グループの名前
+    mode: EvalMode,      // 評価器の種類
+    dfa: Option<Dfa>,    // EvalMode::Dfaの場合に用いるDFA
+    config: Config,      // 探索時のフィルタと上限
+}
+
+/// マッチした範囲を表す型。位置はバイト単位のインデックス。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match<'t> {
+    text: &'t str,
+    start: usize,
+    end: usize,
+}
+
+impl<'t> Match<'t> {
+    fn new(text: &'t str, range: Range<usize>) -> Self {
+        Match {
+            text,
+            start: range.start,
+            end: range.end,
+        }
+    }
+
+    /// マッチ開始位置
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// マッチ終了位置
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// マッチしたバイト単位の範囲
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    /// マッチした文字単位の範囲
+    pub fn char_range(&self) -> Range<usize> {
+        let start = self.text[..self.start].chars().count();
+        let end = start + self.as_str().chars().count();
+        start..end
+    }
+
+    /// マッチした文字列
+    pub fn as_str(&self) -> &'t str {
+        &self.text[self.range()]
+    }
+}
+
+impl Regex {
+    /// 正規表現をパースしてコード生成し、Regexを生成。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::Regex;
+    /// let re = Regex::new("abc|(de|cd)+").unwrap();
+    /// assert!(re.is_match("xxdecd").unwrap());
+    /// ```
+    ///
+    /// # 返り値
+    ///
+    /// 入力された正規表現にエラーがあったり、内部的な実装エラーがある場合はErrを返す。
+    pub fn new(expr: &str) -> Result<Regex, DynError> {
+        Regex::with_mode(expr, EvalMode::Pike)
+    }
+
+    /// 評価器の種類を指定してRegexを生成。
+    ///
+    /// EvalMode::Widthを指定した場合は、Pike VMを用いる。
+    pub fn with_mode(expr: &str, mode: EvalMode) -> Result<Regex, DynError> {
+        RegexBuilder::new(expr).mode(mode).build()
+    }
+
+    /// 元の正規表現
+    pub fn as_str(&self) -> &str {
+        &self.expr
+    }
+
+    /// マッチ全体を含めたグループの数
+    pub fn captures_len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// マッチ全体を含めた各グループの名前を、グループ番号順に返すイテレータ。
+    ///
+    /// マッチ全体と、名前のないグループはNoneとなる。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::Regex;
+    /// let re = Regex::new("(?P<year>\\d{4})-(\\d{2})").unwrap();
+    /// let names: Vec<_> = re.capture_names().collect();
+    /// assert_eq!(names, vec![None, Some("year"), None]);
+    /// ```
+    pub fn capture_names(&self) -> impl Iterator<Item = Option<&str>> + '_ {
+        self.names.iter().map(|name| name.as_deref())
+    }
+
+    /// text中のいずれかの位置でマッチするかを判定。
+    pub fn is_match(&self, text: &str) -> Result<bool, EvalError> {
+        Ok(self.shortest_match(text)?.is_some())
+    }
+
+    /// text中で最も左にあるマッチを返す。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::Regex;
+    /// let re = Regex::new("(ab)+").unwrap();
+    /// let m = re.find("xxababx").unwrap().unwrap();
+    /// assert_eq!(m.range(), 2..6);
+    /// assert_eq!(m.as_str(), "abab");
+    /// ```
+    pub fn find<'t>(&self, text: &'t str) -> Result<Option<Match<'t>>, EvalError> {
+        self.find_at(text, 0)
+    }
+
+    /// textのstart以降で最も左にあるマッチを返す。
+    pub fn find_at<'t>(&self, text: &'t str, start: usize) -> Result<Option<Match<'t>>, EvalError> {
+        let mut slots = [None, None];
+        if self.search(text, start, false, &mut slots)? {
+            Ok(slot_range(&slots, 0).map(|r| Match::new(text, r)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// text中で最も左にあるマッチと、そのマッチでの各グループの範囲を返す。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::Regex;
+    /// let re = Regex::new("(a+)(b|c)").unwrap();
+    /// let caps = re.captures("xxaac").unwrap().unwrap();
+    /// assert_eq!(&caps[0], "aac");
+    /// assert_eq!(&caps[1], "aa");
+    /// assert_eq!(caps.get(2).unwrap().range(), 4..5);
+    /// ```
+    pub fn captures<'t>(&self, text: &'t str) -> Result<Option<Captures<'t>>, EvalError> {
+        self.captures_at(text, 0)
+    }
+
+    /// textのstart以降で最も左にあるマッチと、そのマッチでの各グループの範囲を返す。
+    pub fn captures_at<'t>(
+        &self,
+        text: &'t str,
+        start: usize,
+    ) -> Result<Option<Captures<'t>>, EvalError> {
+        let mut slots = vec![None; self.captures_len() * 2];
+        if self.search(text, start, false, &mut slots)? {
+            Ok(Some(Captures {
+                text,
+                slots: Slots::new(slots, self.names.clone()),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// text中の重なり合わないすべてのマッチを、左から順に返すイテレータを生成。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::Regex;
+    /// let re = Regex::new("a+").unwrap();
+    /// let v: Vec<&str> = re
+    ///     .find_iter("aabaaab")
+    ///     .map(|m| m.unwrap().as_str())
+    ///     .collect();
+    /// assert_eq!(v, vec!["aa", "aaa"]);
+    /// ```
+    pub fn find_iter<'r, 't>(&'r self, text: &'t str) -> FindIter<'r, 't> {
+        FindIter {
+            re: self,
+            text,
+            cursor: Cursor::default(),
+        }
+    }
+
+    /// text中の重なり合わないすべてのマッチについて、各グループの範囲を左から順に返すイテレータを生成。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::Regex;
+    /// let re = Regex::new("(\\w+)=(\\d+)").unwrap();
+    /// let v: Vec<String> = re
+    ///     .captures_iter("a=1, b=22")
+    ///     .map(|caps| caps.unwrap()[2].to_string())
+    ///     .collect();
+    /// assert_eq!(v, vec!["1", "22"]);
+    /// ```
+    pub fn captures_iter<'r, 't>(&'r self, text: &'t str) -> CapturesIter<'r, 't> {
+        CapturesIter {
+            re: self,
+            text,
+            cursor: Cursor::default(),
+        }
+    }
+
+    /// textで最も左にあるマッチを、repで置き換えた文字列を返す。
+    ///
+    /// repには、$1や${name}のようにグループを参照するテンプレート文字列か、
+    /// Capturesを受け取って置き換える文字列を返すクロージャを指定する。
+    /// テンプレートの書式はCaptures::expandを参照。
+    /// マッチしなかった場合は、textをそのまま返す。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::Regex;
+    /// let re = Regex::new("(?P<y>\\d{4})-(?P<m>\\d{2})").unwrap();
+    /// let s = re.replace("2024-01, 2025-02", "$m/$y").unwrap();
+    /// assert_eq!(s, "01/2024, 2025-02");
+    /// ```
+    pub fn replace<'t, R: Replacer>(
+        &self,
+        text: &'t str,
+        rep: R,
+    ) -> Result<Cow<'t, str>, EvalError> {
+        self.replacen(text, 1, rep)
+    }
+
+    /// text中の重なり合わないすべてのマッチを、repで置き換えた文字列を返す。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::{Captures, Regex};
+    /// let re = Regex::new("\\d+").unwrap();
+    /// let s = re
+    ///     .replace_all("a1b22", |caps: &Captures| format!("<{}>", &caps[0]))
+    ///     .unwrap();
+    /// assert_eq!(s, "a<1>b<22>");
+    /// ```
+    pub fn replace_all<'t, R: Replacer>(
+        &self,
+        text: &'t str,
+        rep: R,
+    ) -> Result<Cow<'t, str>, EvalError> {
+        self.replacen(text, 0, rep)
+    }
+
+    /// text中の左からlimit個までのマッチを、repで置き換えた文字列を返す。
+    ///
+    /// limitが0の場合は、すべてのマッチを置き換える。
+    pub fn replacen<'t, R: Replacer>(
+        &self,
+        text: &'t str,
+        limit: usize,
+        mut rep: R,
+    ) -> Result<Cow<'t, str>, EvalError> {
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        let mut dst = String::new();
+        let mut last = None; // 直前のマッチの終了位置
+        for caps in self.captures_iter(text).take(limit) {
+            let caps = caps?;
+            let m = caps.get(0).unwrap(); // マッチ全体は必ず存在する
+            dst.push_str(&text[last.unwrap_or(0)..m.start]);
+            rep.replace_append(&caps, &mut dst);
+            last = Some(m.end);
+        }
+
+        match last {
+            Some(end) => {
+                dst.push_str(&text[end..]);
+                Ok(Cow::Owned(dst))
+            }
+            None => Ok(Cow::Borrowed(text)),
+        }
+    }
+
+    /// text中のマッチを区切りとして、textを分割した部分文字列を返すイテレータを生成。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::Regex;
+    /// let re = Regex::new(",\\s*").unwrap();
+    /// let v: Vec<&str> = re.split("a, b,c").map(|s| s.unwrap()).collect();
+    /// assert_eq!(v, vec!["a", "b", "c"]);
+    /// ```
+    pub fn split<'r, 't>(&'r self, text: &'t str) -> Split<'r, 't> {
+        Split {
+            finder: self.find_iter(text),
+            last: 0,
+        }
+    }
+
+    /// splitと同様にtextを分割するが、返す部分文字列は最大limit個とする。
+    ///
+    /// 最後の部分文字列は、それまでに区切った残りすべてとなる。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::Regex;
+    /// let re = Regex::new(",").unwrap();
+    /// let v: Vec<&str> = re.splitn("a,b,c", 2).map(|s| s.unwrap()).collect();
+    /// assert_eq!(v, vec!["a", "b,c"]);
+    /// ```
+    pub fn splitn<'r, 't>(&'r self, text: &'t str, limit: usize) -> SplitN<'r, 't> {
+        SplitN {
+            splits: self.split(text),
+            limit,
+        }
+    }
+
+    /// マッチが存在する場合に、最初に見つかったマッチの終了位置を返す。
+    ///
+    /// マッチが見つかった時点で探索を打ち切るため、findより高速だが、
+    /// 終了位置はfindが返すものより手前となる場合がある。
+    pub fn shortest_match(&self, text: &str) -> Result<Option<usize>, EvalError> {
+        let mut slots = [None, None];
+        if self.search(text, 0, true, &mut slots)? {
+            Ok(slots[1])
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// 評価器を呼び出し、マッチした範囲をslotsに保存
+    fn search(
+        &self,
+        text: &str,
+        start: usize,
+        earliest: bool,
+        slots: &mut [Option<usize>],
+    ) -> Result<bool, EvalError> {
+        let line = text.as_bytes();
+        match &self.dfa {
+            Some(dfa) => dfa.search(line, start, false, earliest, slots, &self.config),
+            None => evaluator::search(
+                &self.code,
+                line,
+                start,
+                earliest,
+                slots,
+                self.mode,
+                &self.config,
+            ),
+        }
+    }
+}
+
+/// オプションを指定してRegexを生成するためのビルダー
+///
+/// # 利用例
+///
+/// ```
+/// use regex::RegexBuilder;
+/// let re = RegexBuilder::new("^b.").multi_line(true).build().unwrap();
+/// assert_eq!(re.find("a\nbc").unwrap().unwrap().range(), 2..4);
+/// ```
+#[derive(Debug, Clone)]
+pub struct RegexBuilder {
+    expr: String,
+    flags: Flags,
+    mode: EvalMode,
+    size_limit: usize,
+    dfa_size_limit: usize,
+    step_limit: usize,
+    stack_limit: usize,
+    visited_limit: usize,
+}
+
+impl RegexBuilder {
+    pub fn new(expr: &str) -> Self {
+        RegexBuilder {
+            expr: expr.to_string(),
+            flags: Flags::default(),
+            mode: EvalMode::Pike,
+            size_limit: codegen::DEFAULT_SIZE_LIMIT,
+            dfa_size_limit: dfa::DEFAULT_CACHE_LIMIT,
+            step_limit: usize::MAX,
+            stack_limit: usize::MAX,
+            visited_limit: evaluator::DEFAULT_VISITED_LIMIT,
+        }
+    }
+
+    /// trueの場合、^と$は入力の先頭と末尾に加えて、行頭と行末にもマッチ。(?m)と同じ
+    pub fn multi_line(&mut self, yes: bool) -> &mut Self {
+        self.flags.multi_line = yes;
+        self
+    }
+
+    /// trueの場合、.は改行にもマッチ。(?s)と同じ
+    pub fn dot_matches_new_line(&mut self, yes: bool) -> &mut Self {
+        self.flags.dot_nl = yes;
+        self
+    }
+
+    /// trueの場合、大文字と小文字を区別しない。正規表現中で(?i)と指定した場合と同じ
+    pub fn case_insensitive(&mut self, yes: bool) -> &mut Self {
+        self.flags.case_insensitive = yes;
+        self
+    }
+
+    /// 評価器の種類を指定。EvalMode::Widthを指定した場合は、Pike VMを用いる
+    pub fn mode(&mut self, mode: EvalMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// 生成する命令数の上限を指定。超えた場合はbuildがCodeGenError::TooLargeを返す
+    pub fn size_limit(&mut self, limit: usize) -> &mut Self {
+        self.size_limit = limit;
+        self
+    }
+
+    /// EvalMode::Dfaの場合に、DFAのキャッシュが利用するメモリの上限をバイト単位で指定。
+    ///
+    /// 上限を超えた場合はキャッシュを破棄し、その探索はPike VMで行う。
+    pub fn dfa_size_limit(&mut self, limit: usize) -> &mut Self {
+        self.dfa_size_limit = limit;
+        self
+    }
+
+    /// 1回の探索で評価器が進めるステップ数の上限を指定。標準では上限なし。
+    ///
+    /// 1ステップは、深さ優先探索では1命令の実行、Pike VMでは1スレッドを1文字進めること、
+    /// DFAでは1バイトの遷移となる。
+    /// 上限を超えた場合は、マッチングを行うメソッドがEvalError::StepLimitを返す。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::{EvalError, RegexBuilder};
+    /// let re = RegexBuilder::new("(a|b)*[cd]").step_limit(1000).build().unwrap();
+    /// assert!(re.is_match("abc").unwrap());
+    /// let line = "ab".repeat(1000);
+    /// assert!(matches!(re.is_match(&line), Err(EvalError::StepLimit(1000))));
+    /// ```
+    pub fn step_limit(&mut self, limit: usize) -> &mut Self {
+        self.step_limit = limit;
+        self
+    }
+
+    /// EvalMode::Depthの場合に、バックトラック用のスタックの長さの上限を指定。標準では上限なし。
+    ///
+    /// 上限を超えた場合は、マッチングを行うメソッドがEvalError::StackLimitを返す。
+    pub fn stack_limit(&mut self, limit: usize) -> &mut Self {
+        self.stack_limit = limit;
+        self
+    }
+
+    /// EvalMode::Depthの場合に、訪問済みの(pc, sp)を記録するビット集合が利用するメモリの上限を
+    /// バイト単位で指定。標準では64 MiB。
+    ///
+    /// ビット集合は探索ごとに命令数 × (入力長 + 1)ビットを確保する。
+    /// これが上限を超える場合は、マッチングを行うメソッドがEvalError::VisitedLimitを返す。
+    /// 後方参照を含む正規表現ではビット集合を用いないため、この上限は適用されない。
+    pub fn visited_limit(&mut self, limit: usize) -> &mut Self {
+        self.visited_limit = limit;
+        self
+    }
+
+    /// 正規表現をパースしてコード生成し、Regexを生成。
+    ///
+    /// 入力された正規表現にエラーがあったり、内部的な実装エラーがある場合はErrを返す。
+    /// \\1のような後方参照は、EvalMode::Depthの場合のみ利用でき、
+    /// それ以外の場合はEvalError::UnsupportedBackrefを返す。
+    /// (?=e)のような先読みや(?<=e)のような後読みも同様に、
+    /// EvalMode::Depth以外ではEvalError::UnsupportedLookを返す。
+    pub fn build(&self) -> Result<Regex, DynError> {
+        let (code, names, config) = self.compile()?;
+        Ok(Regex {
+            expr: self.expr.clone(),
+            dfa: self.dfa(&code)?,
+            code,
+            names,
+            mode: self.mode,
+            config,
+        })
+    }
+
+    /// 正規表現をパースしてコード生成し、バイト列を対象とするbytes::Regexを生成。
+    ///
+    /// 命令列はUTF-8のバイト単位の命令列に変換するため、buildの場合より命令数が多くなる。
+    /// 入力された正規表現にエラーがあったり、内部的な実装エラーがある場合はErrを返す。
+    pub fn build_bytes(&self) -> Result<bytes::Regex, DynError> {
+        let (code, names, config) = self.compile()?;
+        let code = utf8::compile(&code, self.size_limit)?;
+        Ok(bytes::Regex {
+            expr: self.expr.clone(),
+            dfa: self.dfa(&code)?,
+            code,
+            names,
+            mode: self.mode,
+            config,
+        })
+    }
+
+    /// EvalMode::Dfaの場合はDFAを生成。
+    ///
+    /// DFAはバイト単位の命令列を用いるため、変換後の命令数がsize_limitを超えた場合は
+    /// CodeGenError::TooLargeを返す。
+    fn dfa(&self, code: &[Instruction]) -> Result<Option<Dfa>, CodeGenError> {
+        if self.mode == EvalMode::Dfa {
+            Ok(Some(Dfa::new(code, self.dfa_size_limit, self.size_limit)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// パースとコード生成、最適化を行い、命令列と各グループの名前、探索時の設定を返す
+    fn compile(&self) -> Result<(Vec<Instruction>, CaptureNames, Config), DynError> {
+        let ast = parser::parse_with_flags(&self.expr, self.flags)?;
+        let code = codegen::get_code_with_limit(&ast, self.size_limit)?;
+        let code = optimizer::optimize(code, self.mode == EvalMode::Depth);
+        evaluator::check_supported(&code, self.mode)?;
+        let config = Config {
+            prefilter: Prefilter::new(&ast),
+            step_limit: self.step_limit,
+            stack_limit: self.stack_limit,
+            visited_limit: self.visited_limit,
+        };
+        Ok((code, parser::capture_names(&ast).into(), config))
+    }
+}
+
+impl Display for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+
+/// マッチ全体と、各グループのマッチした範囲。
+///
+/// 0番目がマッチ全体を表し、n番目がn番目のグループを表す。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Captures<'t> {
+    text: &'t str,
+    slots: Slots,
+}
+
+impl<'t> Captures<'t> {
+    /// template中のグループの参照を、そのグループがマッチした文字列に置き換えてdstに追加。
+    ///
+    /// - $n、${n} : n番目のグループ
+    /// - $name、${name} : nameという名前のグループ
+    /// - $$ : $という文字
+    ///
+    /// $nの数字は数字が続く限り、$nameの名前は英数字とアンダースコアが続く限り読み進める。
+    /// そのため、$1年は1番目のグループの後に「年」が続くものとなる。
+    /// 存在しないグループや、マッチに関与しなかったグループは空文字列に置き換える。
+    /// 以上のいずれにも当たらない$は、そのまま$という文字とする。
+    ///
+    /// # 利用例
+    ///
+    /// ```
+    /// use regex::Regex;
+    /// let re = Regex::new("(?P<y>\\d+)/(\\d+)").unwrap();
+    /// let caps = re.captures("2024/5").unwrap().unwrap();
+    /// let mut dst = String::new();
+    /// caps.expand("${y}年$2月 $$", &mut dst);
+    /// assert_eq!(dst, "2024年5月 $");
+    /// ```
+    pub fn expand(&self, template: &str, dst: &mut String) {
+        let mut rest = template;
+        while let Some(i) = rest.find('$') {
+            dst.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+
+            if let Some(r) = rest.strip_prefix('$') {
+                dst.push('$');
+                rest = r;
+                continue;
+            }
+
+            let (name, r) = match rest.strip_prefix('{') {
+                Some(r) => match r.find('}') {
+                    Some(end) => (&r[..end], &r[end + 1..]),
+                    None => ("", rest), // 閉じ括弧がない場合は参照とみなさない
+                },
+                None => {
+                    let end = if rest.starts_with(|c: char| c.is_ascii_digit()) {
+                        rest.find(|c: char| !c.is_ascii_digit())
+                    } else {
+                        rest.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    };
+                    rest.split_at(end.unwrap_or(rest.len()))
+                }
+            };
+
+            if name.is_empty() {
+                dst.push('$');
+                continue;
+            }
+
+            let m = match name.parse::<usize>() {
+                Ok(n) => self.get(n),
+                Err(_) => self.name(name),
+            };
+            if let Some(m) = m {
+                dst.push_str(m.as_str());
+            }
+            rest = r;
+        }
+        dst.push_str(rest);
+    }
+
+    /// i番目のグループがマッチした範囲を返す。
+    ///
+    /// グループがマッチに関与しなかった場合や、iが範囲外の場合はNoneを返す。
+    pub fn get(&self, i: usize) -> Option<Match<'t>> {
+        self.slots.get(i).map(|r| Match::new(self.text, r))
+    }
+
+    /// nameという名前のグループがマッチした範囲を返す。
+    ///
+    /// グループがマッチに関与しなかった場合や、その名前のグループがない場合はNoneを返す。
+    pub fn name(&self, name: &str) -> Option<Match<'t>> {
+        self.slots.name(name).map(|r| Match::new(self.text, r))
+    }
+
+    /// マッチ全体を含めたグループの数
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// 常にfalseを返す。マッチ全体が必ず含まれるため
+    pub fn is_empty(&self) -> bool {
+        self.slots.len() == 0
+    }
+
+    /// 各グループのマッチを順に返すイテレータ
+    pub fn iter<'c>(&'c self) -> impl Iterator<Item = Option<Match<'t>>> + 'c {
+        (0..self.len()).map(|i| self.get(i))
+    }
+}
+
+/// マッチ全体と各グループの範囲を保存したスロットと、各グループの名前。
+///
+/// 文字列を対象とするCapturesと、バイト列を対象とするbytes::Capturesで共有する。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Slots {
+    slots: Vec<Option<usize>>,
+    names: CaptureNames,
+}
+
+impl Slots {
+    pub(super) fn new(slots: Vec<Option<usize>>, names: CaptureNames) -> Self {
+        Slots { slots, names }
+    }
+
+    /// i番目のグループがマッチした範囲
+    pub(super) fn get(&self, i: usize) -> Option<Range<usize>> {
+        slot_range(&self.slots, i)
+    }
+
+    /// nameという名前のグループがマッチした範囲
+    pub(super) fn name(&self, name: &str) -> Option<Range<usize>> {
+        let i = self.names.iter().position(|n| n.as_deref() == Some(name))?;
+        self.get(i)
+    }
+
+    /// マッチ全体を含めたグループの数
+    pub(super) fn len(&self) -> usize {
+        self.slots.len() / 2
+    }
+}
+
+/// slotsに保存された、i番目のグループがマッチした範囲を返す。
+///
+/// 2i番目と2i+1番目のスロットが、開始位置と終了位置を表す。
+pub(super) fn slot_range(slots: &[Option<usize>], i: usize) -> Option<Range<usize>> {
+    match (slots.get(i * 2), slots.get(i * 2 + 1)) {
+        (Some(Some(start)), Some(Some(end))) => Some(*start..*end),
+        _ => None,
+    }
+}
+
+/// i番目のグループがマッチした文字列を返す。
+///
+/// グループがマッチに関与しなかった場合はパニックする。
+impl<'t> Index<usize> for Captures<'t> {
+    type Output = str;
+
+    fn index(&self, i: usize) -> &str {
+        self.get(i)
+            .map(|m| m.as_str())
+            .unwrap_or_else(|| panic!("no group at index '{i}'"))
+    }
+}
+
+/// nameという名前のグループがマッチした文字列を返す。
+///
+/// グループがマッチに関与しなかった場合や、その名前のグループがない場合はパニックする。
+impl<'t, 'n> Index<&'n str> for Captures<'t> {
+    type Output = str;
+
+    fn index(&self, name: &'n str) -> &str {
+        self.name(name)
+            .map(|m| m.as_str())
+            .unwrap_or_else(|| panic!("no group named '{name}'"))
+    }
+}
+
+/// マッチした部分を置き換える文字列を生成するためのトレイト。
+///
+/// &strとStringはテンプレートとして、Captures::expandで展開した文字列に置き換える。
+/// Capturesを受け取るクロージャの場合は、その返り値に置き換える。
+pub trait Replacer {
+    /// capsのマッチ全体を置き換える文字列をdstに追加
+    fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String);
+}
+
+impl Replacer for &str {
+    fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
+        caps.expand(self, dst);
+    }
+}
+
+impl Replacer for String {
+    fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
+        caps.expand(self, dst);
+    }
+}
+
+impl Replacer for &String {
+    fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
+        caps.expand(self, dst);
+    }
+}
+
+impl<F, T> Replacer for F
+where
+    F: FnMut(&Captures<'_>) -> T,
+    T: AsRef<str>,
+{
+    fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
+        dst.push_str(self(caps).as_ref());
+    }
+}
+
+/// 重なり合わないマッチを左から順に探索するための、探索位置。
+///
+/// 文字列とバイト列のいずれを対象とするイテレータでも用いる。
+#[derive(Debug, Default)]
+pub(super) struct Cursor {
+    last_end: usize,           // 次に探索を開始する位置
+    last_match: Option<usize>, // 直前のマッチの終了位置
+}
+
+impl Cursor {
+    /// searchで次のマッチを探索し、その結果を返す。
+    ///
+    /// searchは探索開始位置を受け取り、マッチした範囲と、イテレータが返す値を返す。
+    /// textは探索対象の文字列、もしくはバイト列。
+    pub(super) fn next<T>(
+        &mut self,
+        text: &[u8],
+        mut search: impl FnMut(usize) -> Result<Option<(Range<usize>, T)>, EvalError>,
+    ) -> Option<Result<T, EvalError>> {
+        loop {
+            if self.last_end > text.len() {
+                return None;
+            }
+
+            let (range, item) = match search(self.last_end) {
+                Ok(Some(found)) => found,
+                Ok(None) => return None,
+                Err(e) => {
+                    self.last_end = text.len() + 1; // エラー後は探索を終了
+                    return Some(Err(e));
+                }
+            };
+
+            if range.start == range.end {
+                // 空文字列にマッチした場合は、無限ループしないよう1文字進める
+                // 不正なUTF-8の場合は1バイト進める
+                let len = evaluator::decode_char(text, range.end).map_or(1, |(_, len)| len);
+                self.last_end = range.end + len;
+                if Some(range.end) == self.last_match {
+                    // 直前のマッチの直後にある空文字列は無視
+                    continue;
+                }
+            } else {
+                self.last_end = range.end;
+            }
+
+            self.last_match = Some(range.end);
+            return Some(Ok(item));
+        }
+    }
+}
+
+/// find_iterが返すイテレータ
+#[derive(Debug)]
+pub struct FindIter<'r, 't> {
+    re: &'r Regex,
+    text: &'t str,
+    cursor: Cursor,
+}
+
+impl<'r, 't> Iterator for FindIter<'r, 't> {
+    type Item = Result<Match<'t>, EvalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (re, text) = (self.re, self.text);
+        self.cursor.next(text.as_bytes(), |start| {
+            Ok(re.find_at(text, start)?.map(|m| (m.range(), m)))
+        })
+    }
+}
+
+/// captures_iterが返すイテレータ
+#[derive(Debug)]
+pub struct CapturesIter<'r, 't> {
+    re: &'r Regex,
+    text: &'t str,
+    cursor: Cursor,
+}
+
+impl<'r, 't> Iterator for CapturesIter<'r, 't> {
+    type Item = Result<Captures<'t>, EvalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (re, text) = (self.re, self.text);
+        self.cursor.next(text.as_bytes(), |start| {
+            let caps = re.captures_at(text, start)?;
+            Ok(caps.map(|caps| (caps.get(0).unwrap().range(), caps)))
+        })
+    }
+}
+
+/// splitが返すイテレータ
+#[derive(Debug)]
+pub struct Split<'r, 't> {
+    finder: FindIter<'r, 't>,
+    last: usize, // 次の部分文字列の開始位置。終端を超えた場合は分割を終了
+}
+
+impl<'r, 't> Split<'r, 't> {
+    /// 残りすべてを最後の部分文字列として返す
+    fn rest(&mut self) -> Option<Result<&'t str, EvalError>> {
+        let text = self.finder.text;
+        if self.last > text.len() {
+            return None;
+        }
+        let s = &text[self.last..];
+        self.last = text.len() + 1;
+        Some(Ok(s))
+    }
+}
+
+impl<'r, 't> Iterator for Split<'r, 't> {
+    type Item = Result<&'t str, EvalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let text = self.finder.text;
+        match self.finder.next() {
+            Some(Ok(m)) => {
+                let s = &text[self.last..m.start];
+                self.last = m.end;
+                Some(Ok(s))
+            }
+            Some(Err(e)) => {
+                self.last = text.len() + 1; // エラー後は分割を終了
+                Some(Err(e))
+            }
+            None => self.rest(),
+        }
+    }
+}
+
+/// splitnが返すイテレータ
+#[derive(Debug)]
+pub struct SplitN<'r, 't> {
+    splits: Split<'r, 't>,
+    limit: usize, // 残りの部分文字列の数
+}
+
+impl<'r, 't> Iterator for SplitN<'r, 't> {
+    type Item = Result<&'t str, EvalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.limit {
+            0 => None,
+            1 => {
+                self.limit = 0;
+                self.splits.rest()
+            }
+            _ => {
+                self.limit -= 1;
+                self.splits.next()
+            }
+        }
+    }
+}
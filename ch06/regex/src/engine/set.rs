@@ -0,0 +1,207 @@
+//! 複数の正規表現を、入力の一度の走査でまとめてマッチングする正規表現の集合
+use super::{
+    codegen,
+    evaluator::{self, Config, EvalError, EvalMode},
+    optimizer,
+    parser::{self, Flags},
+    Instruction,
+};
+use crate::helper::DynError;
+
+/// コンパイル済みの正規表現の集合。
+///
+/// すべての正規表現を、i番目の正規表現のマッチをmatch i命令とする1つの命令列にまとめ、
+/// Pike VMで入力を一度だけ走査して、マッチした正規表現を求める。
+/// そのため、正規表現の数によらず、計算量はO(命令数 × 入力長)となる。
+///
+/// 各正規表現がどの位置でマッチしたかは求めないため、
+/// マッチした範囲が必要な場合は、該当する正規表現をRegexとして別にコンパイルする。
+///
+/// # 利用例
+///
+/// ```
+/// use regex::RegexSet;
+/// let set = RegexSet::new(["ERROR", "WARN(ING)?", "\\d{3} ms"]).unwrap();
+/// let m = set.matches("WARNING: took 120 ms").unwrap();
+/// assert_eq!(m.iter().collect::<Vec<_>>(), vec![1, 2]);
+/// assert!(!m.matched(0));
+/// ```
+#[derive(Debug)]
+pub struct RegexSet {
+    exprs: Vec<String>,
+    code: Vec<Instruction>,
+    config: Config, // 探索時の上限
+}
+
+impl RegexSet {
+    /// 各正規表現をパースしてコード生成し、RegexSetを生成。
+    ///
+    /// # 返り値
+    ///
+    /// 入力された正規表現のいずれかにエラーがあったり、内部的な実装エラーがある場合はErrを返す。
+    pub fn new<I, S>(exprs: I) -> Result<RegexSet, DynError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        RegexSetBuilder::new(exprs).build()
+    }
+
+    /// 正規表現の数
+    pub fn len(&self) -> usize {
+        self.exprs.len()
+    }
+
+    /// 正規表現を1つも含まない場合にtrueを返す
+    pub fn is_empty(&self) -> bool {
+        self.exprs.is_empty()
+    }
+
+    /// 元の正規表現を、番号順に並べたもの
+    pub fn patterns(&self) -> &[String] {
+        &self.exprs
+    }
+
+    /// text中のいずれかの位置で、いずれかの正規表現がマッチするかを判定。
+    ///
+    /// いずれかの正規表現がマッチした時点で探索を打ち切る。
+    pub fn is_match(&self, text: &str) -> Result<bool, EvalError> {
+        Ok(self.search(text, true)?.matched_any())
+    }
+
+    /// text中のいずれかの位置でマッチした正規表現を求める。
+    pub fn matches(&self, text: &str) -> Result<SetMatches, EvalError> {
+        self.search(text, false)
+    }
+
+    /// 評価器を呼び出し、マッチした正規表現を求める
+    fn search(&self, text: &str, earliest: bool) -> Result<SetMatches, EvalError> {
+        let mut matched = vec![false; self.len()];
+        evaluator::eval_pike_set(
+            &self.code,
+            text.as_bytes(),
+            earliest,
+            &mut matched,
+            &self.config,
+        )?;
+        Ok(SetMatches { matched })
+    }
+}
+
+/// RegexSetのマッチングで、各正規表現がマッチしたかどうか
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetMatches {
+    matched: Vec<bool>, // i番目の正規表現がマッチした場合はmatched[i]がtrue
+}
+
+impl SetMatches {
+    /// いずれかの正規表現がマッチした場合にtrueを返す
+    pub fn matched_any(&self) -> bool {
+        self.matched.iter().any(|m| *m)
+    }
+
+    /// i番目の正規表現がマッチした場合にtrueを返す。iが範囲外の場合はfalse
+    pub fn matched(&self, i: usize) -> bool {
+        self.matched.get(i).copied().unwrap_or(false)
+    }
+
+    /// 集合中の正規表現の数
+    pub fn len(&self) -> usize {
+        self.matched.len()
+    }
+
+    /// 集合が正規表現を1つも含まない場合にtrueを返す
+    pub fn is_empty(&self) -> bool {
+        self.matched.is_empty()
+    }
+
+    /// マッチした正規表現の番号を、昇順に返すイテレータ
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.matched
+            .iter()
+            .enumerate()
+            .filter_map(|(i, m)| m.then_some(i))
+    }
+}
+
+/// オプションを指定してRegexSetを生成するためのビルダー。
+///
+/// 指定したオプションは、集合中のすべての正規表現に適用される。
+#[derive(Debug, Clone)]
+pub struct RegexSetBuilder {
+    exprs: Vec<String>,
+    flags: Flags,
+    size_limit: usize,
+    step_limit: usize,
+}
+
+impl RegexSetBuilder {
+    pub fn new<I, S>(exprs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        RegexSetBuilder {
+            exprs: exprs.into_iter().map(|e| e.as_ref().to_string()).collect(),
+            flags: Flags::default(),
+            size_limit: codegen::DEFAULT_SIZE_LIMIT,
+            step_limit: usize::MAX,
+        }
+    }
+
+    /// trueの場合、^と$は入力の先頭と末尾に加えて、行頭と行末にもマッチ。(?m)と同じ
+    pub fn multi_line(&mut self, yes: bool) -> &mut Self {
+        self.flags.multi_line = yes;
+        self
+    }
+
+    /// trueの場合、.は改行にもマッチ。(?s)と同じ
+    pub fn dot_matches_new_line(&mut self, yes: bool) -> &mut Self {
+        self.flags.dot_nl = yes;
+        self
+    }
+
+    /// trueの場合、大文字と小文字を区別しない。正規表現中で(?i)と指定した場合と同じ
+    pub fn case_insensitive(&mut self, yes: bool) -> &mut Self {
+        self.flags.case_insensitive = yes;
+        self
+    }
+
+    /// すべての正規表現をまとめた命令数の上限を指定。超えた場合はbuildがCodeGenError::TooLargeを返す
+    pub fn size_limit(&mut self, limit: usize) -> &mut Self {
+        self.size_limit = limit;
+        self
+    }
+
+    /// 1回の探索でPike VMがスレッドを進める回数の上限を指定。標準では上限なし。
+    ///
+    /// 上限を超えた場合は、マッチングを行うメソッドがEvalError::StepLimitを返す。
+    pub fn step_limit(&mut self, limit: usize) -> &mut Self {
+        self.step_limit = limit;
+        self
+    }
+
+    /// 各正規表現をパースし、1つの命令列にまとめてRegexSetを生成。
+    ///
+    /// 入力された正規表現のいずれかにエラーがあったり、内部的な実装エラーがある場合はErrを返す。
+    /// マッチングにはPike VMを用いるため、後方参照や先読み、後読みを含む場合は
+    /// EvalError::UnsupportedBackrefもしくはEvalError::UnsupportedLookを返す。
+    pub fn build(&self) -> Result<RegexSet, DynError> {
+        let asts = self
+            .exprs
+            .iter()
+            .map(|expr| parser::parse_with_flags(expr, self.flags))
+            .collect::<Result<Vec<_>, _>>()?;
+        let code = codegen::get_set_code_with_limit(&asts, self.size_limit)?;
+        let code = optimizer::optimize(code, false);
+        evaluator::check_supported(&code, EvalMode::Pike)?;
+        Ok(RegexSet {
+            exprs: self.exprs.clone(),
+            code,
+            config: Config {
+                step_limit: self.step_limit,
+                ..Config::default()
+            },
+        })
+    }
+}
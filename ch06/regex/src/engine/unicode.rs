@@ -0,0 +1,109 @@
+//! \\p{L}や\\p{Han}のような、Unicodeの一般カテゴリと用字による文字クラスと、
+//! 大文字と小文字の同一視
+#[rustfmt::skip]
+mod tables;
+
+use super::class::CharClass;
+use tables::{CASE_FOLDING, GENERAL_CATEGORIES, SCRIPTS};
+
+/// 複数の細分類からなる一般カテゴリ。略称と正式名の組。
+///
+/// 略称の1文字で始まる細分類の和集合となる。
+const GROUPS: &[(&str, &str)] = &[
+    ("L", "Letter"),
+    ("M", "Mark"),
+    ("N", "Number"),
+    ("P", "Punctuation"),
+    ("S", "Symbol"),
+    ("Z", "Separator"),
+    ("C", "Other"),
+];
+
+/// \\p{name}のnameに対応する文字クラスを返す。
+///
+/// nameには一般カテゴリか用字を、略称（LuやHani）もしくは正式名
+/// （Uppercase_LetterやHan）で指定する。
+/// gc=Lやsc=Hanのように、一般カテゴリと用字のいずれであるかを明示してもよい。
+/// 名前の比較では、大文字と小文字、空白、アンダースコア、ハイフンの違いを無視する。
+///
+/// 該当するものがない場合はNoneを返す。
+pub fn property(name: &str) -> Option<CharClass> {
+    match name.split_once('=') {
+        None => {
+            let name = loose(name);
+            general_category(&name).or_else(|| script(&name))
+        }
+        Some((kind, value)) => match loose(kind).as_str() {
+            "gc" | "generalcategory" => general_category(&loose(value)),
+            "sc" | "script" => script(&loose(value)),
+            _ => None,
+        },
+    }
+}
+
+/// 比較のために、名前を小文字にし、空白、アンダースコア、ハイフンを取り除く
+fn loose(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, ' ' | '_' | '-'))
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// 一般カテゴリの文字クラス。nameはlooseで変換済みの名前
+fn general_category(name: &str) -> Option<CharClass> {
+    let is = |short: &str, long: &str| name == loose(short) || name == loose(long);
+
+    if let Some((_, _, ranges)) = GENERAL_CATEGORIES.iter().find(|(s, l, _)| is(s, l)) {
+        return Some(CharClass::new(ranges.to_vec()));
+    }
+
+    // 細分類の略称がpredを満たすものの和集合
+    let union = |pred: &dyn Fn(&str) -> bool| {
+        let ranges = GENERAL_CATEGORIES
+            .iter()
+            .filter(|(s, _, _)| pred(s))
+            .flat_map(|(_, _, ranges)| ranges.iter().copied())
+            .collect();
+        CharClass::new(ranges)
+    };
+    // 未割り当ての文字は、いずれかの細分類に含まれる文字の補集合
+    let unassigned = || union(&|_| true).negate();
+
+    if is("Cn", "Unassigned") {
+        return Some(unassigned());
+    }
+    if is("LC", "Cased_Letter") {
+        return Some(union(&|s| matches!(s, "Lu" | "Ll" | "Lt")));
+    }
+
+    let (prefix, _) = GROUPS.iter().find(|(s, l)| is(s, l))?;
+    let cls = union(&|s| s.starts_with(prefix));
+    if *prefix == "C" {
+        Some(CharClass::new(
+            [cls.ranges(), unassigned().ranges()].concat(),
+        ))
+    } else {
+        Some(cls)
+    }
+}
+
+/// lo以上hi以下の各文字と、大文字と小文字を区別しない場合に同一視される文字を返す。
+///
+/// 同一視される文字は、単純大文字小文字変換で同じ文字に変換される文字であり、
+/// xがyと同一視される場合は、yもxと同一視される。
+/// テーブルを二分探索するため、範囲の大きさではなく、範囲内の大文字と小文字の対応の数に比例する時間で終わる。
+pub fn simple_fold(lo: char, hi: char) -> impl Iterator<Item = char> {
+    let start = CASE_FOLDING.partition_point(|&(c, _)| c < lo);
+    CASE_FOLDING[start..]
+        .iter()
+        .take_while(move |&&(c, _)| c <= hi)
+        .map(|&(_, v)| v)
+}
+
+/// 用字の文字クラス。nameはlooseで変換済みの名前
+fn script(name: &str) -> Option<CharClass> {
+    SCRIPTS
+        .iter()
+        .find(|(l, s, _)| name == loose(l) || name == loose(s))
+        .map(|(_, _, ranges)| CharClass::new(ranges.to_vec()))
+}
@@ -0,0 +1,173 @@
+//! 文字単位の命令列を、UTF-8のバイト単位の命令列に変換
+use super::{class::CharClass, codegen::CodeGenError, Instruction};
+
+/// 1バイトの範囲の列。UTF-8でエンコードされた1文字、もしくは文字列のバイト列にマッチする
+type Sequence = Vec<(u8, u8)>;
+
+/// 文字単位の命令列を、バイト単位の命令列に変換。
+///
+/// char、class、any、str命令は、その文字をUTF-8でエンコードしたバイト列にマッチする
+/// byte命令の列に変換する。
+/// 複数のバイト列からなる文字クラスは、以下のようにsplit命令で分岐するコードとする。
+///
+/// ```text
+///     split L1, L2
+/// L1: 1つ目のバイト列
+///     jump L3
+/// L2: 2つ目のバイト列
+/// L3:
+/// ```
+///
+/// 変換後の命令数がsize_limitを超えた場合はCodeGenError::TooLargeを返す。
+pub fn compile(code: &[Instruction], size_limit: usize) -> Result<Vec<Instruction>, CodeGenError> {
+    // 各命令をバイト列の選択肢に変換し、変換後のアドレスを求める
+    let mut alts = Vec::with_capacity(code.len());
+    let mut addrs = Vec::with_capacity(code.len() + 1);
+    let mut pc = 0;
+    for inst in code.iter() {
+        let seqs = sequences(inst);
+        addrs.push(pc);
+        pc += seqs.as_ref().map_or(1, |seqs| alt_len(seqs));
+        if pc > size_limit {
+            return Err(CodeGenError::TooLarge(size_limit));
+        }
+        alts.push(seqs);
+    }
+    addrs.push(pc);
+
+    let mut insts = Vec::with_capacity(pc);
+    for (inst, seqs) in code.iter().zip(alts) {
+        match (inst, seqs) {
+            (_, Some(seqs)) => gen_alt(&mut insts, &seqs),
+            (Instruction::Jump(addr), None) => insts.push(Instruction::Jump(addrs[*addr])),
+            (Instruction::Split(addr1, addr2), None) => {
+                insts.push(Instruction::Split(addrs[*addr1], addrs[*addr2]))
+            }
+            (Instruction::Save(n), None) => insts.push(Instruction::Save(*n)),
+            (Instruction::Assert(a), None) => insts.push(Instruction::Assert(*a)),
+            (Instruction::Match(id), None) => insts.push(Instruction::Match(*id)),
+            (Instruction::Backref(n, nocase), None) => {
+                insts.push(Instruction::Backref(*n, *nocase))
+            }
+            (Instruction::Look(look, addr), None) => {
+                insts.push(Instruction::Look(*look, addrs[*addr]))
+            }
+            (Instruction::Byte(lo, hi), None) => insts.push(Instruction::Byte(*lo, *hi)),
+            // 空の文字クラスはどの入力にもマッチしないため、そのまま残す
+            (Instruction::Class(cls), None) => insts.push(Instruction::Class(cls.clone())),
+            (Instruction::Char(_) | Instruction::Any(_) | Instruction::Str(_), None) => {
+                unreachable!("char, any and str always have a sequence")
+            }
+        }
+    }
+
+    Ok(insts)
+}
+
+/// 文字を消費する命令を、UTF-8のバイト列の選択肢に変換。
+///
+/// 文字を消費しない命令や、空の文字クラスの場合はNoneを返す。
+fn sequences(inst: &Instruction) -> Option<Vec<Sequence>> {
+    let cls = match inst {
+        Instruction::Str(s) => return Some(vec![s.bytes().map(|b| (b, b)).collect()]),
+        Instruction::Char(c) => CharClass::new(vec![(*c, *c)]),
+        Instruction::Class(cls) => cls.clone(),
+        Instruction::Any(true) => CharClass::new(vec![('\0', char::MAX)]),
+        Instruction::Any(false) => CharClass::new(vec![('\n', '\n')]).negate(),
+        _ => return None,
+    };
+
+    let mut seqs = Vec::new();
+    for &(lo, hi) in cls.ranges() {
+        // 正規化された範囲はサロゲート領域をまたぐ場合があるため、ここで除く
+        let (lo, hi) = (lo as u32, hi as u32);
+        push_sequences(&mut seqs, lo, hi.min(0xd7ff));
+        push_sequences(&mut seqs, lo.max(0xe000), hi);
+    }
+
+    if seqs.is_empty() {
+        None
+    } else {
+        Some(seqs)
+    }
+}
+
+/// コードポイントの範囲[lo, hi]を、UTF-8のバイト列の範囲に分割してseqsに追加。
+///
+/// 各バイト列の範囲は、各バイトごとの範囲の直積がちょうど元の範囲となるように分割する。
+/// たとえば、[U+0080, U+07FF]は[C2-DF][80-BF]となる。
+fn push_sequences(seqs: &mut Vec<Sequence>, lo: u32, hi: u32) {
+    if lo > hi {
+        return;
+    }
+
+    // エンコード後のバイト数が異なる範囲は分割
+    for max in [0x7f, 0x7ff, 0xffff] {
+        if lo <= max && max < hi {
+            push_sequences(seqs, lo, max);
+            push_sequences(seqs, max + 1, hi);
+            return;
+        }
+    }
+
+    if hi <= 0x7f {
+        seqs.push(vec![(lo as u8, hi as u8)]);
+        return;
+    }
+
+    // 後続バイトが80からBFまでをすべて取らない範囲は分割
+    for i in 1..4 {
+        let m = (1 << (6 * i)) - 1;
+        if lo & !m != hi & !m {
+            if lo & m != 0 {
+                push_sequences(seqs, lo, lo | m);
+                push_sequences(seqs, (lo | m) + 1, hi);
+                return;
+            }
+            if hi & m != m {
+                push_sequences(seqs, lo, (hi & !m) - 1);
+                push_sequences(seqs, hi & !m, hi);
+                return;
+            }
+        }
+    }
+
+    let (mut buf_lo, mut buf_hi) = ([0; 4], [0; 4]);
+    let lo = encode(lo, &mut buf_lo);
+    let hi = encode(hi, &mut buf_hi);
+    seqs.push(lo.iter().zip(hi.iter()).map(|(l, h)| (*l, *h)).collect());
+}
+
+/// コードポイントをUTF-8でエンコード。サロゲート領域は呼び出し元で除かれている
+fn encode(c: u32, buf: &mut [u8; 4]) -> &[u8] {
+    let c = char::from_u32(c).expect("surrogates are excluded");
+    c.encode_utf8(buf).as_bytes()
+}
+
+/// gen_altが生成する命令数
+fn alt_len(seqs: &[Sequence]) -> usize {
+    let bytes: usize = seqs.iter().map(|seq| seq.len()).sum();
+    bytes + (seqs.len() - 1) * 2
+}
+
+/// バイト列の選択肢のコードを生成
+fn gen_alt(insts: &mut Vec<Instruction>, seqs: &[Sequence]) {
+    let end = insts.len() + alt_len(seqs);
+    for (i, seq) in seqs.iter().enumerate() {
+        let last = i + 1 == seqs.len();
+        if !last {
+            // split L1, L2
+            let l1 = insts.len() + 1;
+            let l2 = l1 + seq.len() + 1;
+            insts.push(Instruction::Split(l1, l2));
+        }
+
+        // L1: バイト列
+        insts.extend(seq.iter().map(|&(lo, hi)| Instruction::Byte(lo, hi)));
+
+        if !last {
+            // jump L3
+            insts.push(Instruction::Jump(end));
+        }
+    }
+}
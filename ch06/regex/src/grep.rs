@@ -0,0 +1,1126 @@
+//! grepコマンドと同様に、ファイルや標準入力から正規表現にマッチする行を検索するフロントエンド
+use regex::{
+    bytes::{Match, Regex},
+    DynError, EvalError, EvalMode, RegexBuilder,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    error::Error,
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Condvar, Mutex,
+    },
+    thread,
+};
+
+/// 使い方の説明
+pub const USAGE: &str = "\
+usage: regex [OPTION]... PATTERN [FILE]...
+       regex [OPTION]... -e PATTERN... [FILE]...
+
+  -e PATTERN  use PATTERN for matching; may be given more than once
+  -i          ignore case distinctions
+  -v          select non-matching lines
+  -c          print only a count of selected lines per file
+  -n          print line number with output lines
+  -o          print only the matched parts of a line
+  -l          print only names of files with selected lines
+  -H          print the file name for each match
+  -r          search directories recursively
+  -A NUM      print NUM lines of trailing context
+  -B NUM      print NUM lines of leading context
+  -C NUM      print NUM lines of context unless given by -A or -B
+  -j NUM      search NUM files in parallel (default: number of CPUs)
+  --color[=WHEN]
+              highlight matches; WHEN is never, always or auto (default)
+  --debug     print the compiled code of each PATTERN before searching
+
+With no FILE, read standard input, or the current directory with -r.
+Exit status is 0 if any line is selected, 1 if none, and 2 if an error occurred.
+";
+
+/// 標準入力を表示する際の名前
+const STDIN_NAME: &str = "(standard input)";
+
+/// 色付けに用いるANSIエスケープシーケンス
+const COLOR_MATCH: &str = "\x1b[01;31m"; // マッチした部分。太字の赤
+const COLOR_NAME: &str = "\x1b[35m"; // ファイル名。マゼンタ
+const COLOR_LINENO: &str = "\x1b[32m"; // 行番号。緑
+const COLOR_SEP: &str = "\x1b[36m"; // 区切り。シアン
+const COLOR_RESET: &str = "\x1b[m";
+
+/// コマンドライン引数の誤りを表す型
+#[derive(Debug, PartialEq, Eq)]
+pub enum ArgError {
+    UnknownOption(String),           // 未知のオプション
+    MissingArgument(char),           // 引数を取るオプションに、引数が指定されていない
+    InvalidArgument(String, String), // オプションの引数が不正。オプションと引数を持つ
+    NoPattern,                       // 正規表現が指定されていない
+}
+
+impl Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgError::UnknownOption(opt) => write!(f, "ArgError: unknown option: {opt}"),
+            ArgError::MissingArgument(c) => {
+                write!(f, "ArgError: option requires an argument: -{c}")
+            }
+            ArgError::InvalidArgument(opt, arg) => {
+                write!(f, "ArgError: invalid argument for {opt}: {arg}")
+            }
+            ArgError::NoPattern => write!(f, "ArgError: no pattern given"),
+        }
+    }
+}
+
+impl Error for ArgError {}
+
+/// コマンドラインオプション
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub patterns: Vec<String>,         // -eもしくは最初の引数で指定した正規表現
+    pub paths: Vec<String>,            // 検索するファイルやディレクトリ。空の場合は標準入力
+    pub ignore_case: bool,             // -i
+    pub invert: bool,                  // -v
+    pub count: bool,                   // -c
+    pub line_number: bool,             // -n
+    pub only_matching: bool,           // -o
+    pub files_with_matches: bool,      // -l
+    pub with_filename: bool,           // -H
+    pub recursive: bool,               // -r
+    pub after_context: Option<usize>,  // -A
+    pub before_context: Option<usize>, // -B
+    pub context: Option<usize>,        // -C。-Aや-Bが指定されない場合に用いる
+    pub jobs: Option<usize>,           // -j
+    pub color: Color,                  // --color
+    pub debug: bool,                   // --debug
+}
+
+/// マッチした部分などを色付けする条件
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Never,
+    Always,
+    #[default]
+    Auto, // 標準出力が端末の場合のみ色付けする
+}
+
+/// コマンドライン引数をパースする。argsはプログラム名を除いた引数。
+///
+/// -inのように短いオプションはまとめて指定でき、-eや-Aの引数は-ePATTERNや-A2のように続けてもよい。
+/// オプションとファイル名の順序は問わず、--以降はすべてオプション以外の引数とみなす。
+/// -eが指定されない場合は、オプション以外の最初の引数を正規表現とする。
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Options, ArgError> {
+    let mut opts = Options::default();
+    let mut operands = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            operands.extend(args.by_ref());
+            break;
+        }
+
+        if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (long, None),
+            };
+            match (name, value) {
+                ("debug", None) => opts.debug = true,
+                ("color" | "colour", None) => opts.color = Color::Auto,
+                ("color" | "colour", Some(when)) => {
+                    opts.color = match when {
+                        "never" => Color::Never,
+                        "always" => Color::Always,
+                        "auto" => Color::Auto,
+                        _ => {
+                            let opt = format!("--{name}");
+                            return Err(ArgError::InvalidArgument(opt, when.to_string()));
+                        }
+                    }
+                }
+                _ => return Err(ArgError::UnknownOption(arg)),
+            }
+            continue;
+        }
+
+        // 単独の-は標準入力を表すファイル名
+        let shorts = match arg.strip_prefix('-') {
+            Some(shorts) if !shorts.is_empty() => shorts,
+            _ => {
+                operands.push(arg);
+                continue;
+            }
+        };
+
+        for (i, c) in shorts.char_indices() {
+            let flag = match c {
+                'i' => &mut opts.ignore_case,
+                'v' => &mut opts.invert,
+                'c' => &mut opts.count,
+                'n' => &mut opts.line_number,
+                'o' => &mut opts.only_matching,
+                'l' => &mut opts.files_with_matches,
+                'H' => &mut opts.with_filename,
+                'r' => &mut opts.recursive,
+                'e' | 'A' | 'B' | 'C' | 'j' => {
+                    let rest = &shorts[i + 1..];
+                    let value = if rest.is_empty() {
+                        args.next().ok_or(ArgError::MissingArgument(c))?
+                    } else {
+                        rest.to_string()
+                    };
+
+                    if c == 'e' {
+                        opts.patterns.push(value);
+                    } else {
+                        let n = match value.parse() {
+                            Ok(n) if n > 0 || c != 'j' => n,
+                            _ => return Err(ArgError::InvalidArgument(format!("-{c}"), value)),
+                        };
+                        let field = match c {
+                            'A' => &mut opts.after_context,
+                            'B' => &mut opts.before_context,
+                            'C' => &mut opts.context,
+                            _ => &mut opts.jobs,
+                        };
+                        *field = Some(n);
+                    }
+                    break;
+                }
+                _ => return Err(ArgError::UnknownOption(format!("-{c}"))),
+            };
+            *flag = true;
+        }
+    }
+
+    let mut operands = operands.into_iter();
+    if opts.patterns.is_empty() {
+        opts.patterns
+            .push(operands.next().ok_or(ArgError::NoPattern)?);
+    }
+    opts.paths = operands.collect();
+    Ok(opts)
+}
+
+/// 検索対象の入力
+#[derive(Debug)]
+pub enum Input {
+    Stdin,
+    File(PathBuf),
+    Unreadable(PathBuf, io::Error), // 列挙中に読み込めなかったディレクトリ
+}
+
+impl Input {
+    /// ファイル名の表示やエラーメッセージに用いる名前
+    fn name(&self) -> String {
+        match self {
+            Input::Stdin => STDIN_NAME.to_string(),
+            Input::File(path) | Input::Unreadable(path, _) => path.display().to_string(),
+        }
+    }
+}
+
+/// オプションで指定されたパスから、検索対象の入力を順に求める。
+///
+/// パスが指定されない場合は標準入力を、-rの場合はカレントディレクトリを検索する。
+/// パスが-の場合は標準入力を表す。
+/// -rの場合、ディレクトリ中のファイルを名前順に再帰的に列挙する。
+/// シンボリックリンクは、引数で指定されたもののみを辿る。
+pub fn inputs(opts: &Options) -> Vec<Input> {
+    let mut inputs = Vec::new();
+    if opts.paths.is_empty() {
+        if opts.recursive {
+            walk(Path::new("."), &mut inputs);
+            // ./a/bではなくa/bと表示する
+            for input in inputs.iter_mut() {
+                if let Input::File(path) | Input::Unreadable(path, _) = input {
+                    if let Ok(p) = path.strip_prefix(".") {
+                        *path = p.to_path_buf();
+                    }
+                }
+            }
+        } else {
+            inputs.push(Input::Stdin);
+        }
+        return inputs;
+    }
+
+    for path in opts.paths.iter() {
+        let path = Path::new(path);
+        if path == Path::new("-") {
+            inputs.push(Input::Stdin);
+        } else if opts.recursive && path.is_dir() {
+            walk(path, &mut inputs);
+        } else {
+            inputs.push(Input::File(path.to_path_buf()));
+        }
+    }
+    inputs
+}
+
+/// ディレクトリdir中のファイルを、名前順に再帰的にinputsへ追加
+fn walk(dir: &Path, inputs: &mut Vec<Input>) {
+    let mut entries = match fs::read_dir(dir).and_then(|d| d.collect::<Result<Vec<_>, _>>()) {
+        Ok(entries) => entries,
+        Err(e) => {
+            inputs.push(Input::Unreadable(dir.to_path_buf(), e));
+            return;
+        }
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => walk(&path, inputs),
+            Ok(t) if t.is_file() => inputs.push(Input::File(path)),
+            Ok(_) => (), // シンボリックリンクや特殊ファイル
+            Err(e) => inputs.push(Input::Unreadable(path, e)),
+        }
+    }
+}
+
+/// 検索結果の出力先
+#[derive(Debug)]
+pub struct Output<W: Write> {
+    out: W,
+    printed: bool, // 行を書き出したか。前後の行を表示する場合に、--で区切るかの判定に用いる
+}
+
+impl<W: Write> Output<W> {
+    pub fn new(out: W) -> Self {
+        Output {
+            out,
+            printed: false,
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// 行の検索に用いる正規表現をコンパイル。
+///
+/// DFAで評価できる正規表現はEvalMode::Dfaでコンパイルし、
+/// 後方参照や先読み、後読みを含む場合は深さ優先探索でコンパイルする。
+fn build_regex(expr: &str, ignore_case: bool) -> Result<Regex, DynError> {
+    let build = |mode| {
+        RegexBuilder::new(expr)
+            .case_insensitive(ignore_case)
+            .mode(mode)
+            .build_bytes()
+    };
+    build(EvalMode::Dfa).or_else(|e| match e.downcast_ref::<EvalError>() {
+        Some(EvalError::UnsupportedBackref | EvalError::UnsupportedLook) => build(EvalMode::Depth),
+        _ => Err(e),
+    })
+}
+
+/// lineのstart以降で、reにマッチする空でない部分のうち最も左にあるものを返す。
+///
+/// 空文字列にマッチした場合は、1バイト進めて探索し直す。
+/// 空でないマッチはUTF-8の文字の途中からは始まらないため、文字の途中から探索しても問題ない。
+fn find_non_empty<'t>(
+    re: &Regex,
+    line: &'t [u8],
+    mut start: usize,
+) -> Result<Option<Match<'t>>, EvalError> {
+    while start <= line.len() {
+        match re.find_at(line, start)? {
+            Some(m) if m.start() == m.end() => start = m.end() + 1,
+            m => return Ok(m),
+        }
+    }
+    Ok(None)
+}
+
+/// コンパイル済みの正規表現とオプションを持ち、入力ごとに検索を行う
+#[derive(Debug)]
+pub struct Grep {
+    res: Vec<Regex>, // -eで指定された順の正規表現
+    opts: Options,
+    with_filename: bool, // 各行にファイル名を表示するか
+    before: usize,       // 選択した行の前に表示する行数
+    after: usize,        // 選択した行の後に表示する行数
+    color: bool,         // マッチした部分などを色付けするか
+}
+
+impl Grep {
+    /// 正規表現をコンパイルしてGrepを生成。
+    ///
+    /// 複数の正規表現は、グループの番号や名前が互いに影響しないよう、それぞれ別にコンパイルする。
+    /// 行はバイト列のまま検索するため、UTF-8として不正な行を含むファイルも検索できる。
+    /// ファイル名は、-Hの場合に加えて、複数のパスや-rが指定された場合に表示する。
+    /// -o、-c、-lの場合は行そのものを表示しないため、前後の行も表示しない。
+    pub fn new(opts: Options) -> Result<Grep, DynError> {
+        let res = opts
+            .patterns
+            .iter()
+            .map(|p| build_regex(p, opts.ignore_case))
+            .collect::<Result<_, _>>()?;
+        let with_filename = opts.with_filename || opts.recursive || opts.paths.len() > 1;
+        let lines = !(opts.only_matching || opts.count || opts.files_with_matches);
+        let context = |n: Option<usize>| match n.or(opts.context) {
+            Some(n) if lines => n,
+            _ => 0,
+        };
+        let color = match opts.color {
+            Color::Never => false,
+            Color::Always => true,
+            Color::Auto => io::stdout().is_terminal(),
+        };
+        Ok(Grep {
+            res,
+            with_filename,
+            before: context(opts.before_context),
+            after: context(opts.after_context),
+            color,
+            opts,
+        })
+    }
+
+    /// readerから読み込んだ各行を検索し、結果をoutに書き出す。
+    ///
+    /// nameはファイル名として表示する入力の名前。
+    /// いずれかの行が選択された場合はOk(true)を返す。
+    /// -lの場合は、最初の行が選択された時点で読み込みを打ち切る。
+    pub fn search<R: BufRead, W: Write>(
+        &self,
+        mut reader: R,
+        name: &str,
+        out: &mut Output<W>,
+    ) -> Result<bool, DynError> {
+        let opts = &self.opts;
+        let mut buf = Vec::new();
+        let mut count = 0; // 選択した行の数
+        let mut lineno = 0;
+        let mut before = VecDeque::with_capacity(self.before); // 次に選択した行の前に表示する行と行番号
+        let mut after = 0; // 直前に選択した行の後に、あと何行表示するか
+        let mut last = None; // この入力で最後に表示した行の行番号
+        loop {
+            buf.clear();
+            if reader.read_until(b'\n', &mut buf)? == 0 {
+                break;
+            }
+            lineno += 1;
+
+            let line = trim_newline(&buf);
+            if self.is_match(line)? == opts.invert {
+                if after > 0 {
+                    after -= 1;
+                    self.write_line(out, &mut last, name, lineno, line, false)?;
+                } else if self.before > 0 {
+                    if before.len() == self.before {
+                        before.pop_front();
+                    }
+                    before.push_back((lineno, line.to_vec()));
+                }
+                continue;
+            }
+
+            count += 1;
+            if opts.files_with_matches {
+                break;
+            } else if opts.count {
+                continue;
+            }
+
+            if opts.only_matching {
+                // -vで選択した行にはマッチした部分がないため、何も表示しない
+                for m in self.find_all(line)? {
+                    self.write_prefix(&mut out.out, name, lineno, b':')?;
+                    self.write_colored(&mut out.out, COLOR_MATCH, m.as_bytes())?;
+                    out.out.write_all(b"\n")?;
+                    out.printed = true;
+                }
+            } else {
+                for (n, l) in before.drain(..) {
+                    self.write_line(out, &mut last, name, n, &l, false)?;
+                }
+                self.write_line(out, &mut last, name, lineno, line, true)?;
+                after = self.after;
+            }
+        }
+
+        if opts.files_with_matches {
+            if count > 0 {
+                self.write_colored(&mut out.out, COLOR_NAME, name.as_bytes())?;
+                out.out.write_all(b"\n")?;
+            }
+        } else if opts.count {
+            if self.with_filename {
+                self.write_colored(&mut out.out, COLOR_NAME, name.as_bytes())?;
+                self.write_colored(&mut out.out, COLOR_SEP, b":")?;
+            }
+            writeln!(out.out, "{count}")?;
+        }
+
+        Ok(count > 0)
+    }
+
+    /// lineがいずれかの正規表現にマッチするかを判定
+    fn is_match(&self, line: &[u8]) -> Result<bool, EvalError> {
+        for re in self.res.iter() {
+            if re.is_match(line)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// lineの中で、いずれかの正規表現にマッチする空でない部分を、重ならないよう左から順に返す。
+    ///
+    /// 同じ位置から始まるマッチが複数ある場合は、先に指定された正規表現のものを選ぶ。
+    /// 選んだマッチと重なる他の正規表現のマッチは、選んだマッチの終了位置から探索し直す。
+    fn find_all<'t>(&self, line: &'t [u8]) -> Result<Vec<Match<'t>>, EvalError> {
+        let mut next = self
+            .res
+            .iter()
+            .map(|re| find_non_empty(re, line, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut found = Vec::new();
+        loop {
+            let m = next.iter().flatten().min_by_key(|m| m.start()).copied();
+            let Some(m) = m else {
+                return Ok(found);
+            };
+            found.push(m);
+            for (re, n) in self.res.iter().zip(next.iter_mut()) {
+                if n.is_some_and(|n| n.start() < m.end()) {
+                    *n = find_non_empty(re, line, m.end())?;
+                }
+            }
+        }
+    }
+
+    /// 選択した行か、その前後の行を書き出す。
+    ///
+    /// 前後の行を表示する場合、最後に表示した行lastと連続しない行の前には--を書き出す。
+    /// 前後の行では、ファイル名や行番号の後の区切りを:ではなく-とする。
+    fn write_line<W: Write>(
+        &self,
+        out: &mut Output<W>,
+        last: &mut Option<usize>,
+        name: &str,
+        lineno: usize,
+        line: &[u8],
+        selected: bool,
+    ) -> Result<(), DynError> {
+        if self.has_context() && out.printed && *last != Some(lineno - 1) {
+            self.write_separator(&mut out.out)?;
+        }
+
+        self.write_prefix(
+            &mut out.out,
+            name,
+            lineno,
+            if selected { b':' } else { b'-' },
+        )?;
+        self.write_highlighted(&mut out.out, line)?;
+        out.out.write_all(b"\n")?;
+        out.printed = true;
+        *last = Some(lineno);
+        Ok(())
+    }
+
+    /// 前後の行を表示するか
+    fn has_context(&self) -> bool {
+        self.before > 0 || self.after > 0
+    }
+
+    /// 前後の行を表示する場合に、連続しない行の間に置く区切りを書き出す
+    fn write_separator<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.write_colored(out, COLOR_SEP, b"--")?;
+        out.write_all(b"\n")
+    }
+
+    /// 別に検索した入力の結果partを、outに続けて書き出す。
+    ///
+    /// 前後の行を表示する場合は、1つの出力先に続けて検索した場合と同様に、間を--で区切る。
+    fn append<W: Write>(&self, out: &mut Output<W>, part: &Output<Vec<u8>>) -> io::Result<()> {
+        if self.has_context() && out.printed && part.printed {
+            self.write_separator(&mut out.out)?;
+        }
+        out.out.write_all(&part.out)?;
+        out.printed |= part.printed;
+        Ok(())
+    }
+
+    /// 行の前に表示するファイル名と行番号を、区切りsepとともに書き出す
+    fn write_prefix<W: Write>(
+        &self,
+        out: &mut W,
+        name: &str,
+        lineno: usize,
+        sep: u8,
+    ) -> io::Result<()> {
+        if self.with_filename {
+            self.write_colored(out, COLOR_NAME, name.as_bytes())?;
+            self.write_colored(out, COLOR_SEP, &[sep])?;
+        }
+        if self.opts.line_number {
+            self.write_colored(out, COLOR_LINENO, lineno.to_string().as_bytes())?;
+            self.write_colored(out, COLOR_SEP, &[sep])?;
+        }
+        Ok(())
+    }
+
+    /// lineを書き出す。色付けする場合は、マッチした部分を色付けする
+    fn write_highlighted<W: Write>(&self, out: &mut W, line: &[u8]) -> Result<(), DynError> {
+        if !self.color {
+            out.write_all(line)?;
+            return Ok(());
+        }
+
+        let mut last = 0;
+        for m in self.find_all(line)? {
+            out.write_all(&line[last..m.start()])?;
+            self.write_colored(out, COLOR_MATCH, m.as_bytes())?;
+            last = m.end();
+        }
+        out.write_all(&line[last..])?;
+        Ok(())
+    }
+
+    /// sを書き出す。色付けする場合はcolorで色付けする
+    fn write_colored<W: Write>(&self, out: &mut W, color: &str, s: &[u8]) -> io::Result<()> {
+        if self.color {
+            out.write_all(color.as_bytes())?;
+            out.write_all(s)?;
+            out.write_all(COLOR_RESET.as_bytes())
+        } else {
+            out.write_all(s)
+        }
+    }
+
+    /// 1つの入力を検索し、結果をoutに書き出す
+    fn search_input<W: Write>(&self, input: &Input, out: &mut Output<W>) -> Result<bool, DynError> {
+        match input {
+            Input::Stdin => self.search(io::stdin().lock(), STDIN_NAME, out),
+            Input::File(path) => {
+                let f = File::open(path)?;
+                self.search(BufReader::new(f), &input.name(), out)
+            }
+            Input::Unreadable(_, e) => Err(io::Error::new(e.kind(), e.to_string()).into()),
+        }
+    }
+
+    /// inputsをjobs個のスレッドで並列に検索し、入力ごとの結果を入力の順にfへ渡す。
+    ///
+    /// 各スレッドは未検索の入力を1つずつ取り出し、その結果をメモリ上のOutputに書き出す。
+    /// 結果は入力の順に並べ直してからfへ渡すため、出力はスレッド数によらず、
+    /// 入力を順に検索した場合と同じとなる。
+    /// 先に検索を終えた入力の結果は、それより前の入力の結果をfへ渡すまで保持する。
+    /// 保持する結果が増え続けないよう、まだfへ渡していない最初の入力からjobs個より先の入力は、
+    /// fへ渡すのを待ってから検索する。
+    /// コンパイル済みの正規表現はすべてのスレッドで共有する。
+    /// DFAのキャッシュは探索ごとに専有するため、各スレッドは互いを待たずに検索できる。
+    /// fがfalseを返した場合は、残りの入力の検索を打ち切る。
+    fn search_parallel<F>(&self, inputs: &[Input], jobs: usize, mut f: F)
+    where
+        F: FnMut(&Input, Output<Vec<u8>>, Result<bool, DynError>) -> bool,
+    {
+        let next = AtomicUsize::new(0); // 次に検索する入力のインデックス
+        let stop = AtomicBool::new(false); // trueの場合は検索を打ち切る
+        let passed = (Mutex::new(0), Condvar::new()); // fへ渡した結果の数と、その変化を待つ条件変数
+
+        thread::scope(|scope| {
+            let (tx, rx) = mpsc::channel();
+            for _ in 0..jobs {
+                let tx = tx.clone();
+                let (next, stop, passed) = (&next, &stop, &passed);
+                scope.spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let input = match inputs.get(i) {
+                            Some(input) => input,
+                            None => break,
+                        };
+
+                        // i番目より前の入力は、すべて他のスレッドが検索中か検索済みのため、
+                        // 待っている間もfへ渡す結果は増え続け、デッドロックしない
+                        let (lock, cvar) = passed;
+                        let n = lock.lock().unwrap_or_else(|e| e.into_inner());
+                        let n = cvar
+                            .wait_while(n, |n| i >= *n + jobs && !stop.load(Ordering::Relaxed))
+                            .unwrap_or_else(|e| e.into_inner());
+                        drop(n);
+                        if stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+
+                        let mut part = Output::new(Vec::new());
+                        let result = self.search_input(input, &mut part);
+                        if tx.send((i, part, result)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(tx); // すべてのスレッドが終了した時点で、受信を終える
+
+            let mut pending = BTreeMap::new(); // 順番を待っている結果
+            let mut expected = 0; // 次にfへ渡す入力のインデックス
+            let (lock, cvar) = &passed;
+            for (i, part, result) in rx {
+                pending.insert(i, (part, result));
+                while let Some((part, result)) = pending.remove(&expected) {
+                    let proceed = f(&inputs[expected], part, result);
+                    expected += 1;
+                    {
+                        let mut n = lock.lock().unwrap_or_else(|e| e.into_inner());
+                        *n = expected;
+                        stop.fetch_or(!proceed, Ordering::Relaxed);
+                    }
+                    cvar.notify_all();
+                    if !proceed {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// 検索全体の結果
+#[derive(Debug, Default)]
+struct Status {
+    selected: bool, // いずれかの行が選択された
+    error: bool,    // いずれかの入力でエラーが起きた
+}
+
+impl Status {
+    /// 入力ごとの検索結果を記録し、エラーの場合はprogを付けて標準エラー出力に表示する。
+    ///
+    /// 出力先に書き込めない場合は、以降の検索を行わないようfalseを返す。
+    fn report<W: Write>(
+        &mut self,
+        prog: &str,
+        input: &Input,
+        result: Result<bool, DynError>,
+        out: &mut Output<W>,
+    ) -> bool {
+        match result {
+            Ok(selected) => self.selected |= selected,
+            Err(e) => {
+                self.error = true;
+                // エラーまでに検索した結果を先に表示する
+                if out.flush().is_err() {
+                    return false;
+                }
+                eprintln!("{prog}: {}: {e}", input.name());
+            }
+        }
+        true
+    }
+}
+
+/// オプションに従って検索を行い、grepと同じ終了コードを返す。
+///
+/// いずれかの行が選択された場合は0を、選択されなかった場合は1を、
+/// エラーが起きた場合は2を返す。
+/// 入力ごとのエラーはprogを付けて標準エラー出力に表示し、残りの入力の検索を続ける。
+///
+/// 複数の入力は並列に検索する。入力が1つの場合や-j1の場合は、
+/// 標準入力などを読み込みながら結果を表示できるよう、1つのスレッドで順に検索する。
+pub fn run(prog: &str, opts: Options) -> ExitCode {
+    let debug = opts.debug;
+    let grep = match Grep::new(opts) {
+        Ok(grep) => grep,
+        Err(e) => {
+            eprintln!("{prog}: {e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    if debug {
+        // -iなどを反映した、検索に用いる命令列を表示する
+        for re in grep.res.iter() {
+            re.print_code();
+            println!();
+        }
+    }
+
+    let inputs = inputs(&grep.opts);
+    let jobs = grep
+        .opts
+        .jobs
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .min(inputs.len());
+
+    let mut out = Output::new(BufWriter::new(io::stdout().lock()));
+    let mut status = Status::default();
+    if jobs <= 1 {
+        for input in inputs.iter() {
+            let result = grep.search_input(input, &mut out);
+            if !status.report(prog, input, result, &mut out) {
+                break;
+            }
+        }
+    } else {
+        grep.search_parallel(&inputs, jobs, |input, part, result| {
+            if grep.append(&mut out, &part).is_err() {
+                status.error = true;
+                return false;
+            }
+            status.report(prog, input, result, &mut out)
+        });
+    }
+
+    if out.flush().is_err() || status.error {
+        ExitCode::from(2)
+    } else if status.selected {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(1)
+    }
+}
+
+/// 行末の改行を取り除く。\r\nの場合は\rも取り除く
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+// 単体テスト
+#[cfg(test)]
+mod tests {
+    use super::{inputs, parse_args, ArgError, Color, Grep, Input, Options, Output};
+    use std::{io::Write, sync::mpsc, thread, time::Duration};
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|a| a.to_string()).collect()
+    }
+
+    /// 引数をパースしてtextを検索し、出力と、行が選択されたかを返す。
+    ///
+    /// --colorが指定されない場合は色付けしない。
+    fn grep(s: &str, text: &str) -> (String, bool) {
+        let grep = Grep::new(parse_args(args(&format!("--color=never {s}"))).unwrap()).unwrap();
+        let mut out = Output::new(Vec::new());
+        let selected = grep.search(text.as_bytes(), "f", &mut out).unwrap();
+        (String::from_utf8(out.out).unwrap(), selected)
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let opts = parse_args(args("-in a.*b x.txt -H y.txt")).unwrap();
+        assert_eq!(
+            opts,
+            Options {
+                patterns: vec!["a.*b".to_string()],
+                paths: vec!["x.txt".to_string(), "y.txt".to_string()],
+                ignore_case: true,
+                line_number: true,
+                with_filename: true,
+                ..Options::default()
+            }
+        );
+
+        // -eは複数指定でき、最初の引数もファイル名となる
+        let opts = parse_args(args("-e a -vebc -- -f")).unwrap();
+        assert_eq!(opts.patterns, vec!["a", "bc"]);
+        assert_eq!(opts.paths, vec!["-f"]);
+        assert!(opts.invert);
+
+        let opts = parse_args(args("--debug -rclo a -")).unwrap();
+        assert!(opts.debug && opts.recursive && opts.count);
+        assert!(opts.files_with_matches && opts.only_matching);
+        assert_eq!(opts.paths, vec!["-"]);
+
+        assert_eq!(
+            parse_args(args("-x a")),
+            Err(ArgError::UnknownOption("-x".to_string()))
+        );
+        assert_eq!(
+            parse_args(args("--quiet a")),
+            Err(ArgError::UnknownOption("--quiet".to_string()))
+        );
+        assert_eq!(parse_args(args("-e")), Err(ArgError::MissingArgument('e')));
+        assert_eq!(parse_args(args("-n")), Err(ArgError::NoPattern));
+    }
+
+    #[test]
+    fn test_search() {
+        let text = "apple\nBanana\ncherry\r\nbanana split\n";
+
+        assert_eq!(grep("an", text), ("Banana\nbanana split\n".into(), true));
+        assert_eq!(grep("-i ^b", text), ("Banana\nbanana split\n".into(), true));
+        assert_eq!(grep("-n rr", text), ("3:cherry\n".into(), true));
+        assert_eq!(grep("-H rr", text), ("f:cherry\n".into(), true));
+        assert_eq!(grep("-v an", text), ("apple\ncherry\n".into(), true));
+        assert_eq!(grep("-c an", text), ("2\n".into(), true));
+        assert_eq!(grep("-cv .", text), ("0\n".into(), false));
+        assert_eq!(grep("-l an", text), ("f\n".into(), true));
+        assert_eq!(grep("-l xyz", text), ("".into(), false));
+        assert_eq!(
+            grep("-e apple -e cherry", text),
+            ("apple\ncherry\n".into(), true)
+        );
+
+        // 複数の正規表現はそれぞれ別にコンパイルするため、同じグループ名や後方参照の番号を使える
+        assert_eq!(
+            grep("-e (?<x>p)\\1 -e (?<x>r)\\1", text),
+            ("apple\ncherry\n".into(), true)
+        );
+        // -oでは、いずれかの正規表現にマッチする部分を重ならないよう左から順に表示する
+        assert_eq!(
+            grep("-o -e an -e nan -e pl", text),
+            ("pl\nan\nan\nan\nan\npl\n".into(), true)
+        );
+        assert_eq!(grep("-o -e ab -e b+", "abbb\n").0, "ab\nbb\n");
+        assert_eq!(grep("-o -e x* -e b", "abb\n").0, "b\nb\n");
+
+        // -oはマッチした部分ごとに表示し、空文字列へのマッチは表示しない
+        assert_eq!(
+            grep("-on an", text),
+            ("2:an\n2:an\n4:an\n4:an\n".into(), true)
+        );
+        assert_eq!(grep("-o p*", text), ("pp\np\n".into(), true));
+        assert_eq!(grep("-ov an", text), ("".into(), true));
+
+        // DFAで評価できない後方参照や先読みも扱える
+        assert_eq!(grep("-o (an)\\1", text), ("anan\nanan\n".into(), true));
+        assert_eq!(grep("-o a(?=p)", text), ("a\n".into(), true));
+
+        // UTF-8として不正な行も検索できる
+        let grep = Grep::new(parse_args(args("b")).unwrap()).unwrap();
+        let mut out = Output::new(Vec::new());
+        assert!(grep.search(&b"a\xff\nb\xff\n"[..], "f", &mut out).unwrap());
+        assert_eq!(out.out, b"b\xff\n");
+    }
+
+    #[test]
+    fn test_context() {
+        let opts = parse_args(args("-A1 -B 2 -C3 --colour=always x")).unwrap();
+        assert_eq!(opts.after_context, Some(1));
+        assert_eq!(opts.before_context, Some(2));
+        assert_eq!(opts.context, Some(3));
+        assert_eq!(opts.color, Color::Always);
+        assert_eq!(parse_args(args("-j4 x")).unwrap().jobs, Some(4));
+        assert_eq!(
+            parse_args(args("-j 0 x")),
+            Err(ArgError::InvalidArgument("-j".into(), "0".into()))
+        );
+        assert_eq!(
+            parse_args(args("-A x a")),
+            Err(ArgError::InvalidArgument("-A".into(), "x".into()))
+        );
+        assert_eq!(
+            parse_args(args("--color=red a")),
+            Err(ArgError::InvalidArgument("--color".into(), "red".into()))
+        );
+
+        let text = "1\n2 x\n3\n4\n5\n6 x\n7\n8\n9\n10 x\n";
+        assert_eq!(
+            grep("-n -A1 x", text).0,
+            "2:2 x\n3-3\n--\n6:6 x\n7-7\n--\n10:10 x\n"
+        );
+        assert_eq!(
+            grep("-B2 x", text).0,
+            "1\n2 x\n--\n4\n5\n6 x\n--\n8\n9\n10 x\n"
+        );
+        // 前後の行が重なる場合は区切らない
+        assert_eq!(grep("-C2 -H 6", text).0, "f-4\nf-5\nf:6 x\nf-7\nf-8\n");
+        assert_eq!(grep("-C1 -B0 [26]", text).0, "2 x\n3\n--\n6 x\n7\n");
+        assert_eq!(grep("-nvA1 x", "a x\nb\nc x\nd x\n").0, "2:b\n3-c x\n");
+
+        // -o、-c、-lでは前後の行を表示しない
+        assert_eq!(grep("-c -C1 6", text).0, "1\n");
+        assert_eq!(grep("-o -C1 6", text).0, "6\n");
+
+        // 入力をまたいでも--で区切る
+        let grep = Grep::new(parse_args(args("--color=never -A1 x")).unwrap()).unwrap();
+        let mut out = Output::new(Vec::new());
+        grep.search(&b"x\n"[..], "a", &mut out).unwrap();
+        grep.search(&b"y\n"[..], "b", &mut out).unwrap();
+        grep.search(&b"x\ny\n"[..], "c", &mut out).unwrap();
+        assert_eq!(out.out, b"x\n--\nx\ny\n");
+    }
+
+    #[test]
+    fn test_color() {
+        let text = "ab ab\nc\n";
+        assert_eq!(
+            grep("--color=always b", text).0,
+            "a\x1b[01;31mb\x1b[m a\x1b[01;31mb\x1b[m\n"
+        );
+        assert_eq!(
+            grep("--color=always -Hn -A1 a", text).0,
+            "\x1b[35mf\x1b[m\x1b[36m:\x1b[m\x1b[32m1\x1b[m\x1b[36m:\x1b[m\x1b[01;31ma\x1b[mb \x1b[01;31ma\x1b[mb\n\
+             \x1b[35mf\x1b[m\x1b[36m-\x1b[m\x1b[32m2\x1b[m\x1b[36m-\x1b[mc\n"
+        );
+        assert_eq!(
+            grep("--color=always -o b+", text).0,
+            "\x1b[01;31mb\x1b[m\n\x1b[01;31mb\x1b[m\n"
+        );
+        // 空文字列へのマッチは色付けしない
+        assert_eq!(grep("--color=always x*", "ab\n").0, "ab\n");
+        assert_eq!(
+            grep("--color=always -e a -e b", text).0,
+            "\x1b[01;31ma\x1b[m\x1b[01;31mb\x1b[m \x1b[01;31ma\x1b[m\x1b[01;31mb\x1b[m\n"
+        );
+    }
+
+    #[test]
+    fn test_inputs() {
+        let dir = std::env::temp_dir().join(format!("regex_grep_test_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("b.txt"), "hello\n").unwrap();
+        std::fs::write(dir.join("a.txt"), "world\n").unwrap();
+        std::fs::write(dir.join("sub").join("c.txt"), "hello world\n").unwrap();
+
+        let names = |opts: &Options| -> Vec<String> {
+            inputs(opts)
+                .iter()
+                .map(|i| match i {
+                    Input::Stdin => "-".to_string(),
+                    Input::File(p) | Input::Unreadable(p, _) => {
+                        p.strip_prefix(&dir).unwrap().display().to_string()
+                    }
+                })
+                .collect()
+        };
+
+        // -rの場合はファイルを名前順に再帰的に列挙する
+        let d = dir.display();
+        let opts = parse_args(args(&format!("-r x {d}"))).unwrap();
+        assert_eq!(names(&opts), vec!["a.txt", "b.txt", "sub/c.txt"]);
+
+        // -rでない場合、ディレクトリもファイルとして扱い、読み込み時にエラーとなる
+        let opts = parse_args(args(&format!("x {d}"))).unwrap();
+        assert_eq!(names(&opts), vec![""]);
+        let grep = Grep::new(opts).unwrap();
+        let input = inputs(&grep.opts).pop().unwrap();
+        assert!(grep
+            .search_input(&input, &mut Output::new(Vec::new()))
+            .is_err());
+
+        let opts = parse_args(args("x")).unwrap();
+        assert!(matches!(inputs(&opts).as_slice(), [Input::Stdin]));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parallel() {
+        let dir = std::env::temp_dir().join(format!("regex_grep_par_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut inputs = Vec::new();
+        for i in 0..40 {
+            let path = dir.join(format!("{i:02}.txt"));
+            let text: String = (0..i * 50).map(|n| format!("{} {n}\n", n % 7)).collect();
+            std::fs::write(&path, text).unwrap();
+            inputs.push(Input::File(path));
+        }
+        inputs.insert(5, Input::File(dir.join("none.txt"))); // 存在しないファイル
+
+        for expr in ["-n 3", "-C1 -H ^3", "-c 5$", "-l 39"] {
+            let grep =
+                Grep::new(parse_args(args(&format!("--color=never {expr}"))).unwrap()).unwrap();
+
+            // 1つのスレッドで順に検索した結果
+            let mut expected = Output::new(Vec::new());
+            let expected_ok: Vec<_> = inputs
+                .iter()
+                .map(|input| grep.search_input(input, &mut expected).is_ok())
+                .collect();
+
+            for jobs in [2, 8] {
+                let mut out = Output::new(Vec::new());
+                let mut ok = Vec::new();
+                grep.search_parallel(&inputs, jobs, |_, part, result| {
+                    grep.append(&mut out, &part).unwrap();
+                    ok.push(result.is_ok());
+                    true
+                });
+                assert_eq!(out.out, expected.out, "{expr} -j{jobs}");
+                assert_eq!(ok, expected_ok);
+            }
+        }
+
+        // falseを返した時点で打ち切る
+        let grep = Grep::new(parse_args(args("x")).unwrap()).unwrap();
+        let mut n = 0;
+        grep.search_parallel(&inputs, 4, |_, _, _| {
+            n += 1;
+            n < 3
+        });
+        assert_eq!(n, 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parallel_fifo() {
+        // 名前付きパイプの書き込み側は、読み込み側が開くまでopenを終えない。
+        // これを用いて、各スレッドがどの入力を検索しているかを調べる
+        let dir = std::env::temp_dir().join(format!("regex_grep_fifo_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<_> = (0..3)
+            .map(|i| {
+                let path = dir.join(format!("{i}.fifo"));
+                let status = std::process::Command::new("mkfifo")
+                    .arg(&path)
+                    .status()
+                    .unwrap();
+                assert!(status.success());
+                path
+            })
+            .collect();
+        let inputs: Vec<_> = paths.iter().cloned().map(Input::File).collect();
+        let grep = Grep::new(parse_args(args("--color=never a")).unwrap()).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|scope| {
+            let searcher = scope.spawn(|| {
+                let mut out = Output::new(Vec::new());
+                grep.search_parallel(&inputs, 2, |_, part, result| {
+                    assert!(result.unwrap());
+                    grep.append(&mut out, &part).unwrap();
+                    true
+                });
+                out.out
+            });
+            // i番目の入力に書き込み側として1行を書き込む。開けた時点でiを送る
+            let write = |i: usize| {
+                let (tx, path) = (tx.clone(), &paths[i]);
+                scope.spawn(move || {
+                    let mut f = std::fs::File::create(path).unwrap();
+                    tx.send(i).unwrap();
+                    writeln!(f, "a{i}").unwrap();
+                });
+            };
+            let timeout = Duration::from_secs(10);
+
+            // 最初の入力で待っている間も、別のスレッドが次の入力を検索する
+            write(1);
+            let second = rx.recv_timeout(timeout).ok();
+
+            // 最初の入力の結果を渡すまでは、jobs個より先の入力は検索しない
+            write(2);
+            let ahead = rx.recv_timeout(Duration::from_millis(100)).ok();
+
+            // 検索が止まったままにならないよう、すべての入力を開けてから検査する
+            write(0);
+            let mut opened: Vec<_> = [second, ahead].into_iter().flatten().collect();
+            while opened.len() < 3 {
+                opened.push(rx.recv_timeout(timeout).unwrap());
+            }
+            assert_eq!(second, Some(1));
+            assert_eq!(ahead, None);
+            assert_eq!(searcher.join().unwrap(), b"a0\na1\na2\n");
+        });
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
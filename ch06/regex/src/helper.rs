@@ -22,3 +22,24 @@ where
 }
 
 pub type DynError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+// 単体テスト。プライベート関数もテスト可能
+#[cfg(test)]
+mod tests {
+    use super::{safe_add, SafeAdd};
+
+    #[test]
+    fn test_safe_add() {
+        let n: usize = 10;
+        assert_eq!(Some(30), n.safe_add(&20));
+
+        let n: usize = !0; // 2^64 - 1 (64 bits CPU)
+        assert_eq!(None, n.safe_add(&1));
+
+        let mut n: usize = 10;
+        assert!(safe_add(&mut n, &20, || ()).is_ok());
+
+        let mut n: usize = !0;
+        assert!(safe_add(&mut n, &1, || ()).is_err());
+    }
+}
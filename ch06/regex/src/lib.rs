@@ -8,8 +8,17 @@
 //! let line = "cdefdefdef"; // マッチ対象文字列
 //! regex::do_matching(expr, line, true); // 幅優先探索でマッチング
 //! regex::print(expr); // 正規表現のASTと命令列を表示
+//!
+//! // 一度コンパイルした正規表現を使い回して探索
+//! let re = regex::Regex::new(expr).unwrap();
+//! assert_eq!(re.find("xxcdef").unwrap().unwrap().as_str(), "cdef");
 //! ```
 mod engine;
 mod helper;
 
-pub use engine::{do_matching, print};
+pub use engine::{
+    bytes, do_matching, do_matching_with, print, print_with, Captures, CapturesIter, CodeGenError,
+    EvalError, EvalMode, FindIter, Match, ParseError, Regex, RegexBuilder, RegexSet,
+    RegexSetBuilder, Replacer, SetMatches, Split, SplitN,
+};
+pub use helper::DynError;
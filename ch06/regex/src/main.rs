@@ -1,79 +1,39 @@
-mod engine;
-mod helper;
-
-use helper::DynError;
-use std::{
-    env,
-    fs::File,
-    io::{BufRead, BufReader},
-};
-
-fn main() -> Result<(), DynError> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() <= 2 {
-        eprintln!("usage: {} regex file", args[0]);
-        return Err("invalid arguments".into());
-    } else {
-        match_file(&args[1], &args[2])?;
-    }
-
-    Ok(())
-}
+mod grep;
 
-/// ファイルをオープンし、行ごとにマッチングを行う。
-///
-/// マッチングはそれぞれの行頭から1文字ずつずらして行い、
-/// いずれかにマッチした場合に、その行がマッチしたものとみなす。
-///
-/// たとえば、abcdという文字列があった場合、以下の順にマッチが行われ、
-/// このいずれかにマッチした場合、与えられた正規表現にマッチする行と判定する。
+use std::{env, path::Path, process::ExitCode};
+
+/// grepと同様に、正規表現にマッチする行をファイルや標準入力から検索する。
 ///
-/// - abcd
-/// - bcd
-/// - cd
-/// - d
-fn match_file(expr: &str, file: &str) -> Result<(), DynError> {
-    let f = File::open(file)?;
-    let reader = BufReader::new(f);
-
-    engine::print(expr)?;
-    println!();
-
-    for line in reader.lines() {
-        let line = line?;
-        for (i, _) in line.char_indices() {
-            if engine::do_matching(expr, &line[i..], true)? {
-                println!("{line}");
-                break;
-            }
+/// 使い方はgrep::USAGEを参照。
+fn main() -> ExitCode {
+    let mut args = env::args();
+    let prog = args
+        .next()
+        .as_deref()
+        .and_then(|p| Path::new(p).file_name())
+        .map_or("regex".to_string(), |p| p.to_string_lossy().into_owned());
+
+    match grep::parse_args(args) {
+        Ok(opts) => grep::run(&prog, opts),
+        Err(e) => {
+            eprintln!("{prog}: {e}");
+            eprint!("{}", grep::USAGE);
+            ExitCode::from(2)
         }
     }
-
-    Ok(())
 }
 
-// 単体テスト。プライベート関数もテスト可能
+// 単体テスト
 #[cfg(test)]
 mod tests {
-    use crate::{
-        engine::do_matching,
-        helper::{safe_add, SafeAdd},
+    use regex::{
+        bytes, do_matching, do_matching_with, Captures, CodeGenError, EvalError, EvalMode,
+        ParseError, Regex, RegexBuilder, RegexSet, RegexSetBuilder,
+    };
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
     };
-
-    #[test]
-    fn test_safe_add() {
-        let n: usize = 10;
-        assert_eq!(Some(30), n.safe_add(&20));
-
-        let n: usize = !0; // 2^64 - 1 (64 bits CPU)
-        assert_eq!(None, n.safe_add(&1));
-
-        let mut n: usize = 10;
-        assert!(safe_add(&mut n, &20, || ()).is_ok());
-
-        let mut n: usize = !0;
-        assert!(safe_add(&mut n, &1, || ()).is_err());
-    }
 
     #[test]
     fn test_matching() {
@@ -99,4 +59,1353 @@ mod tests {
         assert!(!do_matching("(ab|cd)+", "", true).unwrap());
         assert!(!do_matching("abc?", "acb", true).unwrap());
     }
+
+    #[test]
+    fn test_matching_pike() {
+        let m = |expr: &str, line: &str| do_matching_with(expr, line, EvalMode::Pike);
+
+        // パースエラー
+        assert!(m("+b", "bbb").is_err());
+        assert!(m("|b", "bbb").is_err());
+
+        // パース成功、マッチ成功
+        assert!(m("abc|def", "def").unwrap());
+        assert!(m("(abc)*", "abcabc").unwrap());
+        assert!(m("(ab|cd)+", "abcdcd").unwrap());
+        assert!(m("abc?", "ab").unwrap());
+        assert!(m("((((a*)*)*)*)", "aaaaaaaaa").unwrap());
+        assert!(m("(a*)*b", "aaaaaaaaab").unwrap());
+        assert!(m("a**b", "b").unwrap());
+        assert!(m("(a?)*b", "aab").unwrap()); // 空文字にマッチする繰り返し
+
+        // パース成功、マッチ失敗
+        assert!(!m("abc|def", "efa").unwrap());
+        assert!(!m("(ab|cd)+", "").unwrap());
+        assert!(!m("abc?", "acb").unwrap());
+        assert!(!m("(a?)*b", "aac").unwrap());
+
+        // a?^n a^nのような正規表現でも線形時間で終了する
+        let n = 100;
+        let expr = format!("{}{}", "a?".repeat(n), "a".repeat(n));
+        assert!(m(&expr, &"a".repeat(n)).unwrap());
+        assert!(!m(&expr, &"a".repeat(n - 1)).unwrap());
+    }
+
+    #[test]
+    fn test_regex() {
+        let re = Regex::new("(ab|cd)+e").unwrap();
+        assert!(re.is_match("xxabcde").unwrap());
+        assert!(!re.is_match("abcd").unwrap());
+
+        // 最も左にあるマッチを、可能な限り長く返す
+        let m = re.find("xxabcdeabe").unwrap().unwrap();
+        assert_eq!(m.range(), 2..7);
+        assert_eq!(m.as_str(), "abcde");
+        assert_eq!(re.shortest_match("xxabcdeabe").unwrap(), Some(7));
+        assert!(re.find("abcd").unwrap().is_none());
+
+        // 位置はバイト単位で、文字単位の範囲も取得可能
+        let re = Regex::new("にほ+").unwrap();
+        let m = re.find("あにほほん").unwrap().unwrap();
+        assert_eq!(m.range(), 3..12);
+        assert_eq!(m.char_range(), 1..4);
+
+        let re = Regex::new("a+|b").unwrap();
+        let v: Vec<_> = re
+            .find_iter("aabxaaa")
+            .map(|m| m.unwrap().range())
+            .collect();
+        assert_eq!(v, vec![0..2, 2..3, 4..7]);
+
+        // 空文字列へのマッチ
+        let re = Regex::new("a*").unwrap();
+        let v: Vec<_> = re.find_iter("baab").map(|m| m.unwrap().range()).collect();
+        assert_eq!(v, vec![0..0, 1..3, 4..4]);
+    }
+
+    #[test]
+    fn test_captures() {
+        for mode in [EvalMode::Depth, EvalMode::Pike, EvalMode::Dfa] {
+            let re = Regex::with_mode("(a+)(b|(c))d", mode).unwrap();
+            assert_eq!(re.captures_len(), 4);
+
+            let caps = re.captures("xaabd").unwrap().unwrap();
+            assert_eq!(caps.len(), 4);
+            assert_eq!(&caps[0], "aabd");
+            assert_eq!(&caps[1], "aa");
+            assert_eq!(&caps[2], "b");
+            assert!(caps.get(3).is_none()); // マッチに関与しないグループ
+            assert_eq!(caps.get(1).unwrap().range(), 1..3);
+
+            let caps = re.captures("acd").unwrap().unwrap();
+            assert_eq!(&caps[2], "c");
+            assert_eq!(&caps[3], "c");
+
+            assert!(re.captures("aab").unwrap().is_none());
+
+            // 繰り返し中のグループは最後の繰り返しの範囲となる
+            let re = Regex::with_mode("(ab|cd)+", mode).unwrap();
+            let caps = re.captures("abcdab").unwrap().unwrap();
+            assert_eq!(&caps[1], "ab");
+            assert_eq!(caps.get(1).unwrap().range(), 4..6);
+
+            // 空のグループ
+            let re = Regex::with_mode("a()b", mode).unwrap();
+            let caps = re.captures("ab").unwrap().unwrap();
+            assert_eq!(caps.get(1).unwrap().range(), 1..1);
+
+            // 入れ子のグループ
+            let re = Regex::with_mode("((a)(b))*", mode).unwrap();
+            let v: Vec<_> = re
+                .captures("abab")
+                .unwrap()
+                .unwrap()
+                .iter()
+                .map(|m| m.map(|m| m.range()))
+                .collect();
+            assert_eq!(v, vec![Some(0..4), Some(2..4), Some(2..3), Some(3..4)]);
+        }
+    }
+
+    #[test]
+    fn test_named_groups() {
+        for mode in [EvalMode::Depth, EvalMode::Pike, EvalMode::Dfa] {
+            // 名前付きグループと番号を持たないグループ
+            let re =
+                Regex::with_mode("(?P<year>\\d{4})-(?:\\d{2})-(?<日>\\d{2})(x)?", mode).unwrap();
+            assert_eq!(re.captures_len(), 4);
+            let names: Vec<_> = re.capture_names().collect();
+            assert_eq!(names, vec![None, Some("year"), Some("日"), None]);
+
+            let caps = re.captures("date: 2024-01-23").unwrap().unwrap();
+            assert_eq!(&caps["year"], "2024");
+            assert_eq!(&caps["日"], "23");
+            assert_eq!(&caps[1], "2024");
+            assert_eq!(caps.name("日").unwrap().range(), 14..16);
+            assert!(caps.name("month").is_none());
+            assert!(caps.get(3).is_none());
+
+            // 番号を持たないグループも、繰り返しや選択の範囲を表す
+            let re = Regex::with_mode("(?:ab|cd)+(e)", mode).unwrap();
+            let caps = re.captures("xabcde").unwrap().unwrap();
+            assert_eq!(&caps[0], "abcde");
+            assert_eq!(&caps[1], "e");
+
+            // フラグの指定は、:の後の式のみに有効
+            let re = Regex::with_mode("(?i:ab)c", mode).unwrap();
+            assert!(re.is_match("ABc").unwrap());
+            assert!(!re.is_match("ABC").unwrap());
+        }
+
+        let re = bytes::Regex::new("(?P<key>\\w+)=(?P<value>\\w+)").unwrap();
+        let caps = re.captures(b"\xffk=v").unwrap().unwrap();
+        assert_eq!(&caps["key"], b"k");
+        assert_eq!(&caps["value"], b"v");
+        let names: Vec<_> = re.capture_names().collect();
+        assert_eq!(names, vec![None, Some("key"), Some("value")]);
+
+        // 後方参照の番号は、名前付きグループにも割り当てられる
+        let re = Regex::with_mode("(?:x)(?<c>a)\\1", EvalMode::Depth).unwrap();
+        assert!(re.is_match("xaa").unwrap());
+
+        // 名前の誤りや重複はエラー
+        for expr in [
+            "(?P<>a)",
+            "(?<1a>a)",
+            "(?P<a)",
+            "(?Pa)",
+            "(?<a>a)(?<a>b)",
+            "(?:a",
+            "(?i-:a)",
+        ] {
+            let e = Regex::new(expr).unwrap_err();
+            assert!(e.downcast_ref::<ParseError>().is_some(), "{expr}");
+        }
+    }
+
+    #[test]
+    fn test_replace() {
+        for mode in [EvalMode::Depth, EvalMode::Pike, EvalMode::Dfa] {
+            let re = Regex::with_mode("(?P<key>\\w+)=(\\d+)", mode).unwrap();
+            let text = "a=1, bc=23";
+
+            // テンプレートによる置き換え
+            assert_eq!(re.replace(text, "$2:$key").unwrap(), "1:a, bc=23");
+            assert_eq!(re.replace_all(text, "${2}:${key}").unwrap(), "1:a, 23:bc");
+            assert_eq!(re.replacen(text, 2, "[$0]").unwrap(), "[a=1], [bc=23]");
+            assert_eq!(re.replace_all(text, "$$1").unwrap(), "$1, $1");
+
+            // 存在しないグループは空文字列、参照とならない$はそのまま
+            assert_eq!(re.replace(text, "<$3$none>").unwrap(), "<>, bc=23");
+            assert_eq!(re.replace(text, "$ ${key").unwrap(), "$ ${key, bc=23");
+            assert_eq!(re.replace(text, "$2番").unwrap(), "1番, bc=23");
+
+            // クロージャによる置き換え
+            let s = re
+                .replace_all(text, |caps: &Captures| {
+                    let n: u32 = caps[2].parse().unwrap();
+                    format!("{}={}", &caps["key"], n * 2)
+                })
+                .unwrap();
+            assert_eq!(s, "a=2, bc=46");
+
+            // マッチしない場合は元の文字列をそのまま返す
+            let s = re.replace_all("none", "x").unwrap();
+            assert!(matches!(s, std::borrow::Cow::Borrowed("none")));
+
+            // 空文字列へのマッチはfind_iterと同じ位置で置き換える
+            let re = Regex::with_mode("a*", mode).unwrap();
+            assert_eq!(re.replace_all("baab", "-").unwrap(), "-b-b-");
+            let re = Regex::with_mode("x*", mode).unwrap();
+            assert_eq!(re.replace_all("あい", "|").unwrap(), "|あ|い|");
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        for mode in [EvalMode::Depth, EvalMode::Pike, EvalMode::Dfa] {
+            let split = |expr: &str, text: &'static str| -> Vec<&'static str> {
+                let re = Regex::with_mode(expr, mode).unwrap();
+                let v = re.split(text).map(|s| s.unwrap()).collect();
+                v
+            };
+            let splitn = |expr: &str, text: &'static str, n| -> Vec<&'static str> {
+                let re = Regex::with_mode(expr, mode).unwrap();
+                let v = re.splitn(text, n).map(|s| s.unwrap()).collect();
+                v
+            };
+
+            assert_eq!(split("[,;]\\s*", "a, b;c"), vec!["a", "b", "c"]);
+            assert_eq!(split(",", ",a,,b,"), vec!["", "a", "", "b", ""]);
+            assert_eq!(split(",", ""), vec![""]);
+            assert_eq!(split("x*", "ab"), vec!["", "a", "b", ""]);
+            assert_eq!(split("、", "あ、い"), vec!["あ", "い"]);
+
+            assert_eq!(splitn(",", "a,b,c", 2), vec!["a", "b,c"]);
+            assert_eq!(splitn(",", "a,b,c", 5), vec!["a", "b", "c"]);
+            assert_eq!(splitn(",", "a,b,c", 1), vec!["a,b,c"]);
+            assert!(splitn(",", "a,b,c", 0).is_empty());
+        }
+
+        // 探索中のエラーは、それ以降の分割を終了する
+        let re = RegexBuilder::new("(a|b)*[cd]")
+            .step_limit(100)
+            .build()
+            .unwrap();
+        let line = "ab".repeat(100);
+        let mut it = re.split(&line);
+        assert!(matches!(it.next(), Some(Err(EvalError::StepLimit(100)))));
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn test_regex_set() {
+        let exprs = [
+            "ERROR",
+            "WARN(ING)?",
+            "^\\[\\d+\\]",
+            "(ab|cd)+e$",
+            "\\bid=\\w+",
+            "[あ-ん]{2}",
+        ];
+        let set = RegexSet::new(exprs).unwrap();
+        assert_eq!(set.len(), exprs.len());
+        assert_eq!(set.patterns()[1], "WARN(ING)?");
+
+        let lines = [
+            "[12] ERROR: id=abc",
+            "WARN ababcde",
+            "x[12] WARNING ひらがな",
+            "nothing here",
+            "xid=1 cde",
+            "",
+        ];
+        for line in lines {
+            // 各正規表現を個別にマッチングした結果と一致する
+            let m = set.matches(line).unwrap();
+            let expected: Vec<_> = exprs
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| Regex::new(e).unwrap().is_match(line).unwrap())
+                .map(|(i, _)| i)
+                .collect();
+            assert_eq!(m.iter().collect::<Vec<_>>(), expected, "{line}");
+            assert_eq!(m.matched_any(), !expected.is_empty());
+            assert_eq!(set.is_match(line).unwrap(), !expected.is_empty());
+        }
+
+        let m = set.matches("[12] ERROR: id=abc").unwrap();
+        assert!(m.matched(0) && m.matched(2) && m.matched(4));
+        assert!(!m.matched(1) && !m.matched(100));
+
+        // オプションはすべての正規表現に適用される
+        let set = RegexSetBuilder::new(["error", "^warn"])
+            .case_insensitive(true)
+            .multi_line(true)
+            .build()
+            .unwrap();
+        let m = set.matches("x\nWARN ERROR").unwrap();
+        assert_eq!(m.iter().collect::<Vec<_>>(), vec![0, 1]);
+
+        // 空の集合はどの入力にもマッチしない
+        let set = RegexSet::new(Vec::<String>::new()).unwrap();
+        assert!(set.is_empty());
+        assert!(!set.is_match("abc").unwrap());
+
+        // Pike VMで扱えない正規表現や、誤りを含む正規表現はエラー
+        assert!(RegexSet::new(["a", "(a)\\1"]).is_err());
+        assert!(RegexSet::new(["a", "(?=a)"]).is_err());
+        assert!(RegexSet::new(["a", "(b"]).is_err());
+
+        // 上限
+        let set = RegexSetBuilder::new(["a{1000}", "b"])
+            .size_limit(100)
+            .build();
+        assert!(set.is_err());
+        let set = RegexSetBuilder::new(["(a|b)*c", "x"])
+            .step_limit(10)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            set.matches("abababab"),
+            Err(EvalError::StepLimit(10))
+        ));
+    }
+
+    #[test]
+    fn test_send_sync() {
+        // コンパイル済みの命令列は読み込みのみのため、スレッド間で共有できる
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Regex>();
+        assert_send_sync::<bytes::Regex>();
+        assert_send_sync::<RegexSet>();
+
+        let re = Regex::with_mode("(a|b)+c", EvalMode::Dfa).unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        assert_eq!(re.find("xxababc").unwrap().unwrap().range(), 2..7);
+                    }
+                });
+            }
+        });
+
+        // DFAのキャッシュは探索ごとに専有するため、長い探索の間も他のスレッドは待たずに探索できる
+        let re = Regex::with_mode("(a|b)+[cd]", EvalMode::Dfa).unwrap();
+        let long = "ab".repeat(1 << 22);
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                assert!(re.find(&long).unwrap().is_none());
+                done.store(true, Ordering::SeqCst);
+            });
+            std::thread::sleep(Duration::from_millis(20));
+            for _ in 0..100 {
+                assert_eq!(re.find("xxababc").unwrap().unwrap().range(), 2..7);
+            }
+            assert!(!done.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn test_class() {
+        for mode in [
+            EvalMode::Depth,
+            EvalMode::Width,
+            EvalMode::Pike,
+            EvalMode::Dfa,
+        ] {
+            let m = |expr: &str, line: &str| do_matching_with(expr, line, mode);
+
+            // パースエラー
+            assert!(m("[abc", "a").is_err());
+            assert!(m("[z-a]", "a").is_err());
+            assert!(m("[a-", "a").is_err());
+            assert!(m("[\\q]", "a").is_err());
+
+            // パース成功、マッチ成功
+            assert!(m("[a-z0-9_]+", "ab_9z").unwrap());
+            assert!(m("[^a-z]", "A").unwrap());
+            assert!(m("[]a]", "]").unwrap()); // 先頭の]は文字
+            assert!(m("[a-]", "-").unwrap()); // 末尾の-は文字
+            assert!(m("[-a]", "-").unwrap()); // 先頭の-は文字
+            assert!(m("[\\]\\-]", "-").unwrap());
+            assert!(m("[^^]", "a").unwrap());
+            assert!(m("[あ-ん]+", "ひらがな").unwrap());
+            assert!(m("[^a]", "あ").unwrap());
+            assert!(m("x[0-9]*y", "xy").unwrap());
+
+            // パース成功、マッチ失敗
+            assert!(!m("[a-z0-9_]", "A").unwrap());
+            assert!(!m("[^a-z]", "a").unwrap());
+            assert!(!m("[^a]", "").unwrap());
+            assert!(!m("[^^]", "^").unwrap());
+        }
+
+        let re = Regex::new("[0-9]+-[0-9]+").unwrap();
+        assert_eq!(
+            re.find("tel: 03-1234").unwrap().unwrap().as_str(),
+            "03-1234"
+        );
+    }
+
+    #[test]
+    fn test_unicode_class() {
+        for mode in [
+            EvalMode::Depth,
+            EvalMode::Width,
+            EvalMode::Pike,
+            EvalMode::Dfa,
+        ] {
+            let m = |expr: &str, line: &str| do_matching_with(expr, line, mode);
+
+            // 用字
+            assert!(m("^\\p{Han}+$", "漢字").unwrap());
+            assert!(m("^\\p{Hiragana}+$", "ひらがな").unwrap());
+            assert!(m("^\\p{Katakana}+$", "カタカナ").unwrap());
+            assert!(m("^\\p{sc=Hani}$", "𠮷").unwrap());
+            assert!(!m("\\p{Han}", "かな").unwrap());
+
+            // 一般カテゴリ
+            assert!(m("^\\p{L}+$", "aÄあ漢").unwrap());
+            assert!(m("^\\pL+$", "aÄあ漢").unwrap());
+            assert!(m("^\\p{Lu}\\p{Ll}$", "Ää").unwrap());
+            assert!(m("^\\p{Uppercase_Letter}$", "Ω").unwrap());
+            assert!(m("^\\p{gc=Nd}+$", "0１٣").unwrap());
+            assert!(m("^\\p{P}+$", "、。「」").unwrap());
+            assert!(m("^\\p{Zs}$", "\u{3000}").unwrap());
+            assert!(m("^\\p{Cn}$", "\u{378}").unwrap());
+            assert!(m("^\\p{C}$", "\u{378}").unwrap());
+            assert!(!m("\\p{L}", "123、").unwrap());
+
+            // 否定と、文字クラス中での利用
+            assert!(m("^\\P{Han}+$", "かな").unwrap());
+            assert!(m("^\\PL+$", "123").unwrap());
+            assert!(m("^[\\p{Hiragana}\\p{Katakana}ー]+$", "ひらがなカタカナー").unwrap());
+            assert!(m("^[^\\p{Han}]$", "a").unwrap());
+            assert!(!m("[\\P{L}]", "aあ").unwrap());
+
+            // 名前の大文字と小文字やアンダースコアの違いは無視する
+            assert!(m("^\\p{ uppercase-letter }$", "A").unwrap());
+            assert!(m("^\\p{HIRAGANA}$", "あ").unwrap());
+            assert!(m("(?i)^\\p{Lu}$", "a").unwrap());
+
+            // パースエラー
+            assert!(m("\\p{Kanji}", "漢").is_err());
+            assert!(m("\\p{Han", "漢").is_err());
+            assert!(m("\\p{xx=Han}", "漢").is_err());
+            assert!(m("\\p", "p").is_err());
+        }
+
+        let re = Regex::new("\\p{Han}+").unwrap();
+        let v: Vec<_> = re
+            .find_iter("東京都と大阪府")
+            .map(|m| m.unwrap().as_str())
+            .collect();
+        assert_eq!(v, vec!["東京都", "大阪府"]);
+    }
+
+    #[test]
+    fn test_any_anchor() {
+        for mode in [
+            EvalMode::Depth,
+            EvalMode::Width,
+            EvalMode::Pike,
+            EvalMode::Dfa,
+        ] {
+            let m = |expr: &str, line: &str| do_matching_with(expr, line, mode);
+
+            assert!(m("a.c", "abc").unwrap());
+            assert!(m("a.c", "aあc").unwrap());
+            assert!(m("a.*c", "abbbc").unwrap());
+            assert!(m("^ab$", "ab").unwrap());
+            assert!(m("a\\.c", "a.c").unwrap());
+            assert!(m("a\\$", "a$").unwrap());
+            assert!(m("(a|b$)c", "ac").unwrap());
+
+            assert!(!m("a.c", "a\nc").unwrap()); // .は改行にマッチしない
+            assert!(!m("a.c", "ac").unwrap());
+            assert!(!m("^ab$", "abc").unwrap());
+            assert!(!m("a^b", "ab").unwrap());
+            assert!(!m("a\\.c", "abc").unwrap());
+        }
+
+        let build = |expr: &str, multi_line: bool, dot_nl: bool, mode: EvalMode| {
+            RegexBuilder::new(expr)
+                .multi_line(multi_line)
+                .dot_matches_new_line(dot_nl)
+                .mode(mode)
+                .build()
+                .unwrap()
+        };
+
+        for mode in [EvalMode::Depth, EvalMode::Pike, EvalMode::Dfa] {
+            // 先頭と末尾のアサーション
+            let re = build("^ab", false, false, mode);
+            assert!(re.is_match("abc").unwrap());
+            assert!(!re.is_match("cab").unwrap());
+            let v: Vec<_> = re.find_iter("abab").map(|m| m.unwrap().range()).collect();
+            assert_eq!(v, vec![0..2]);
+
+            let re = build("ab$", false, false, mode);
+            assert_eq!(re.find("abab").unwrap().unwrap().range(), 2..4);
+            assert!(!re.is_match("ab\nc").unwrap());
+
+            let re = build("x|^$", false, false, mode);
+            assert!(re.is_match("").unwrap());
+            assert!(!re.is_match("a").unwrap());
+
+            // 行頭と行末のアサーション
+            let re = build("^[a-z]+$", true, false, mode);
+            let v: Vec<_> = re
+                .find_iter("abc\n123\nxyz")
+                .map(|m| m.unwrap().as_str())
+                .collect();
+            assert_eq!(v, vec!["abc", "xyz"]);
+
+            // .を改行にもマッチさせる
+            let re = build("a.b", false, true, mode);
+            assert!(re.is_match("a\nb").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_word_boundary() {
+        for mode in [
+            EvalMode::Depth,
+            EvalMode::Width,
+            EvalMode::Pike,
+            EvalMode::Dfa,
+        ] {
+            let m = |expr: &str, line: &str| do_matching_with(expr, line, mode);
+
+            assert!(m("\\bab\\b", "ab").unwrap());
+            assert!(m("a\\Bb", "ab").unwrap());
+            assert!(m("\\Aab\\z", "ab").unwrap());
+            assert!(m("a\\b", "a").unwrap());
+
+            assert!(!m("a\\bb", "ab").unwrap());
+            assert!(!m("\\Ba", "a").unwrap());
+            assert!(!m("\\Aab\\z", "abc").unwrap());
+        }
+
+        let multi_line = |expr: &str, mode: EvalMode| {
+            RegexBuilder::new(expr)
+                .multi_line(true)
+                .mode(mode)
+                .build()
+                .unwrap()
+        };
+
+        for mode in [EvalMode::Depth, EvalMode::Pike, EvalMode::Dfa] {
+            // 識別子の探索
+            let re = Regex::with_mode("\\bfoo\\b", mode).unwrap();
+            let v: Vec<_> = re
+                .find_iter("foo foobar _foo (foo) foo")
+                .map(|m| m.unwrap().range())
+                .collect();
+            assert_eq!(v, vec![0..3, 17..20, 22..25]);
+
+            // 探索の開始位置より前の文字も、境界の判定に用いる
+            let re = Regex::with_mode("\\B\\w+", mode).unwrap();
+            let v: Vec<_> = re.find_iter("ab cd").map(|m| m.unwrap().as_str()).collect();
+            assert_eq!(v, vec!["b", "d"]);
+
+            // \wはASCIIの文字のみにマッチするため、マルチバイト文字との間は境界となる
+            let re = Regex::with_mode("\\b\\w+\\b", mode).unwrap();
+            assert_eq!(re.find("あabcい").unwrap().unwrap().as_str(), "abc");
+
+            // \Aと\zは複数行モードでも入力の先頭と末尾にのみマッチする
+            let re = multi_line("\\A\\w+$|^\\w+\\z", mode);
+            let v: Vec<_> = re
+                .find_iter("ab\ncd\nef")
+                .map(|m| m.unwrap().as_str())
+                .collect();
+            assert_eq!(v, vec!["ab", "ef"]);
+
+            let re = bytes::Regex::with_mode("\\b\\w+\\b", mode).unwrap();
+            let m = re.find(b"\xff\xfekey\xff").unwrap().unwrap();
+            assert_eq!(m.range(), 2..5);
+        }
+    }
+
+    #[test]
+    fn test_repeat() {
+        for mode in [
+            EvalMode::Depth,
+            EvalMode::Width,
+            EvalMode::Pike,
+            EvalMode::Dfa,
+        ] {
+            let m = |expr: &str, line: &str| do_matching_with(expr, line, mode);
+
+            // パースエラー
+            assert!(m("{2}", "aa").is_err());
+            assert!(m("a{", "a").is_err());
+            assert!(m("a{x}", "a").is_err());
+            assert!(m("a{,2}", "a").is_err());
+            assert!(m("a{3,2}", "a").is_err());
+            assert!(m("a{2", "a").is_err());
+            assert!(m("a{99999999999999999999}", "a").is_err());
+            assert!(m("a{1001}", "a").is_err());
+            assert!(m("a{0,1001}", "a").is_err());
+
+            // パース成功、マッチ成功
+            assert!(m("a{3}", "aaa").unwrap());
+            assert!(m("^a{1000}$", &"a".repeat(1000)).unwrap());
+            assert!(m("^((?:){1000}){1000}$", "").unwrap());
+            assert!(m("^a{2,}$", "aaaaa").unwrap());
+            assert!(m("^a{2,4}$", "aaaa").unwrap());
+            assert!(m("^(ab){0,2}$", "").unwrap());
+            assert!(m("^[0-9]{4}-[0-9]{2}$", "2024-01").unwrap());
+            assert!(m("a\\{2\\}", "a{2}").unwrap());
+
+            // パース成功、マッチ失敗
+            assert!(!m("a{3}", "aa").unwrap());
+            assert!(!m("^a{2,}$", "a").unwrap());
+            assert!(!m("^a{2,4}$", "aaaaa").unwrap());
+        }
+
+        let re = Regex::new("(a|b){2,3}").unwrap();
+        let caps = re.captures("xabba").unwrap().unwrap();
+        assert_eq!(&caps[0], "abb");
+        assert_eq!(&caps[1], "b");
+
+        // 命令数の上限
+        let err = RegexBuilder::new("(a{100}){100}")
+            .size_limit(1000)
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CodeGenError>(),
+            Some(CodeGenError::TooLarge(1000))
+        ));
+        assert!(Regex::new("(a{100}){100}").is_ok());
+
+        let expr = "a{3,2}";
+        let err = Regex::new(expr).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ParseError>(),
+            Some(ParseError::InvalidRepeat(1))
+        ));
+
+        // 命令を生成しない式の繰り返しも、回数の上限を超えるとエラー
+        let err = Regex::new("(?:){3000000000}").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ParseError>(),
+            Some(ParseError::TooLargeRepeat(4))
+        ));
+    }
+
+    #[test]
+    fn test_lazy() {
+        // 深さ優先探索とPike VMは、いずれもPerlと同じ最左優先のマッチを返す
+        let inputs = [
+            ("a+?", "aaa", "a"),
+            ("a*?", "aaa", ""),
+            ("a??", "aaa", ""),
+            ("a*?b", "aaab", "aaab"),
+            ("<.+?>", "<a><b>", "<a>"),
+            ("<.+>", "<a><b>", "<a><b>"),
+            ("a{2,4}?", "aaaa", "aa"),
+            ("a{2,}?", "aaaa", "aa"),
+            ("(a|ab)(c|bcd)", "abcd", "abcd"),
+            ("x(a+?)(a*)y", "xaaay", "xaaay"),
+            // 貪欲さの異なる二重の繰り返し
+            ("(?:a*)*?", "aaa", ""),
+            ("(a*)*?", "aaa", ""),
+            ("(?:a*?)*", "aaa", ""),
+            ("(a*?)*", "aaa", ""),
+            ("(?:a*?)*b", "aab", "aab"),
+        ];
+
+        for mode in [EvalMode::Depth, EvalMode::Pike, EvalMode::Dfa] {
+            for (expr, text, expected) in inputs {
+                let re = Regex::with_mode(expr, mode).unwrap();
+                let m = re.find(text).unwrap().unwrap();
+                assert_eq!(m.as_str(), expected, "expr = {expr}, mode = {mode:?}");
+            }
+
+            let re = Regex::with_mode("x(a+?)(a*)y", mode).unwrap();
+            let caps = re.captures("xaaay").unwrap().unwrap();
+            assert_eq!(&caps[1], "a");
+            assert_eq!(&caps[2], "aa");
+
+            let re = Regex::with_mode("(a*?)(a*)", mode).unwrap();
+            let caps = re.captures("aa").unwrap().unwrap();
+            assert_eq!(&caps[1], "");
+            assert_eq!(&caps[2], "aa");
+
+            // 外側が非貪欲な場合は0回の繰り返しとなり、グループはマッチしない
+            let re = Regex::with_mode("(a*)*?", mode).unwrap();
+            let caps = re.captures("aaa").unwrap().unwrap();
+            assert_eq!(caps.get(1), None);
+            let re = Regex::with_mode("(a*?)*?", mode).unwrap();
+            let caps = re.captures("aaa").unwrap().unwrap();
+            assert_eq!(caps.get(1), None);
+
+            // 外側が貪欲な場合は1回の繰り返しで、内側の非貪欲な繰り返しが空文字列にマッチ
+            let re = Regex::with_mode("(a*?)*", mode).unwrap();
+            let caps = re.captures("aaa").unwrap().unwrap();
+            assert_eq!(caps.get(1).map(|m| m.range()), Some(0..0));
+        }
+
+        for mode in [
+            EvalMode::Depth,
+            EvalMode::Width,
+            EvalMode::Pike,
+            EvalMode::Dfa,
+        ] {
+            assert!(do_matching_with("a+?b", "aab", mode).unwrap());
+            assert!(do_matching_with("^a??$", "a", mode).unwrap());
+            assert!(!do_matching_with("^a{2,3}?$", "a", mode).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        for mode in [
+            EvalMode::Depth,
+            EvalMode::Width,
+            EvalMode::Pike,
+            EvalMode::Dfa,
+        ] {
+            let m = |expr: &str, line: &str| do_matching_with(expr, line, mode);
+
+            // パースエラー
+            assert!(m("\\q", "q").is_err());
+            assert!(m("\\x4", "A").is_err());
+            assert!(m("\\xZZ", "A").is_err());
+            assert!(m("\\u{}", "A").is_err());
+            assert!(m("\\u{110000}", "A").is_err());
+            assert!(m("\\u{D800}", "A").is_err());
+            assert!(m("\\u{1234567}", "A").is_err());
+            assert!(m("\\u{41", "A").is_err());
+            assert!(m("[\\q]", "q").is_err());
+
+            // パース成功、マッチ成功
+            assert!(m("^\\d+$", "0123456789").unwrap());
+            assert!(m("^\\w+$", "abc_XYZ_09").unwrap());
+            assert!(m("^\\s+$", " \t\r\n\u{3000}").unwrap());
+            assert!(m("^\\D\\W\\S$", "a-b").unwrap());
+            assert!(m("a\\nb\\tc\\r\\0", "a\nb\tc\r\0").unwrap());
+            assert!(m("\\x41\\x{3042}", "Aあ").unwrap());
+            assert!(m("\\u{1F642}", "\u{1F642}").unwrap());
+            assert!(m("^[\\d\\s]+$", "1 2\t3").unwrap());
+            assert!(m("^[^\\d]+$", "abc").unwrap());
+            assert!(m("^[\\D]+$", "abc").unwrap());
+            assert!(m("^[\\x41-\\x43]+$", "ABC").unwrap());
+            assert!(m("^[\\d-z]+$", "1-z").unwrap());
+            assert!(m("^[\\n\\t]+$", "\n\t").unwrap());
+            assert!(m("\\-", "-").unwrap());
+
+            // パース成功、マッチ失敗
+            assert!(!m("\\d", "a").unwrap());
+            assert!(!m("\\w", "あ").unwrap());
+            assert!(!m("\\s", "a").unwrap());
+            assert!(!m("\\S", " ").unwrap());
+            assert!(!m("^[^\\d]+$", "a1").unwrap());
+            assert!(!m("^[\\d-z]+$", "y").unwrap());
+        }
+
+        let re = Regex::new("(\\d{4})-(\\d{2})-(\\d{2})").unwrap();
+        let caps = re.captures("date: 2024-01-31").unwrap().unwrap();
+        assert_eq!(&caps[1], "2024");
+        assert_eq!(&caps[3], "31");
+    }
+
+    #[test]
+    fn test_flags() {
+        for mode in [
+            EvalMode::Depth,
+            EvalMode::Width,
+            EvalMode::Pike,
+            EvalMode::Dfa,
+        ] {
+            let m = |expr: &str, line: &str| do_matching_with(expr, line, mode);
+
+            // パースエラー
+            assert!(m("(?)a", "a").is_err());
+            assert!(m("(?x)a", "a").is_err());
+            assert!(m("(?i-)a", "a").is_err());
+            assert!(m("(?--i)a", "a").is_err());
+            assert!(m("(?i", "a").is_err());
+
+            // パース成功、マッチ成功
+            assert!(m("(?i)abc", "AbC").unwrap());
+            assert!(m("(?i)[a-c]+", "ABC").unwrap());
+            assert!(m("(?i)[^a]", "b").unwrap());
+            assert!(m("(?i)\\x41", "a").unwrap());
+            assert!(m("(?i)straße", "STRAßE").unwrap());
+            assert!(m("(?i)ÄÖÜ", "äöü").unwrap());
+            assert!(m("(?i)σ", "Σ").unwrap());
+            assert!(m("(?i)ж", "Ж").unwrap());
+            assert!(m("a(?i)b", "aB").unwrap());
+            assert!(m("(?i)a(?-i)b", "Ab").unwrap());
+            assert!(m("((?i)a)b", "Ab").unwrap());
+            assert!(m("(?i)a|b", "B").unwrap());
+            assert!(m("(?s)a.b", "a\nb").unwrap());
+            assert!(m("(?ms)^a.$", "a\n").unwrap());
+
+            // 単純大文字小文字変換で同一視される文字は、どちらの向きでもマッチ
+            let orbits = [
+                ['K', 'k', '\u{212a}'], // K(U+212A)はケルビン記号
+                ['S', 's', 'ſ'],
+                ['Σ', 'σ', 'ς'],
+                ['Ω', 'ω', '\u{2126}'], // Ω(U+2126)はオーム記号
+            ];
+            for orbit in orbits {
+                for x in orbit {
+                    for y in orbit {
+                        assert!(m(&format!("(?i){x}"), &y.to_string()).unwrap());
+                        assert!(m(&format!("(?i)[{x}]"), &y.to_string()).unwrap());
+                    }
+                }
+            }
+            assert!(m("(?i)[a-z]", "\u{212a}").unwrap());
+            assert!(m("(?i)[\u{2100}-\u{2130}]", "k").unwrap());
+            assert!(m("(?i)[^\u{212a}]", "x").unwrap());
+
+            // パース成功、マッチ失敗
+            assert!(!m("abc", "AbC").unwrap());
+            assert!(!m("(?i)[^a]", "A").unwrap());
+            assert!(!m("(?i)\\d", "a").unwrap());
+            assert!(!m("a(?i)b", "Ab").unwrap());
+            assert!(!m("(?i)a(?-i)b", "AB").unwrap());
+            assert!(!m("((?i)a)b", "AB").unwrap());
+            assert!(!m("a.b", "a\nb").unwrap());
+            assert!(!m("(?i)[^k]", "\u{212a}").unwrap());
+            assert!(!m("(?i)[^\u{212a}]", "K").unwrap());
+            assert!(!m("(?i)ς", "x").unwrap());
+        }
+
+        let build = |expr: &str, mode: EvalMode| {
+            RegexBuilder::new(expr)
+                .case_insensitive(true)
+                .mode(mode)
+                .build()
+                .unwrap()
+        };
+
+        for mode in [EvalMode::Depth, EvalMode::Pike, EvalMode::Dfa] {
+            let re = build("hello", mode);
+            assert_eq!(re.find("Say HeLLo").unwrap().unwrap().range(), 4..9);
+
+            let re = build("a(?-i)b", mode);
+            assert!(re.is_match("Ab").unwrap());
+            assert!(!re.is_match("AB").unwrap());
+
+            let re = Regex::with_mode("(?m)^b$", mode).unwrap();
+            assert_eq!(re.find("a\nb\nc").unwrap().unwrap().range(), 2..3);
+        }
+    }
+
+    #[test]
+    fn test_bytes() {
+        // 文字列を対象とする場合と同じ結果となる
+        let inputs = [
+            ("abc|(de|cd)+", "xxdecdx"),
+            ("[あ-ん]+", "カタカナとひらがな"),
+            ("[^a]+", "aあ\u{7ff}\u{800}\u{ffff}\u{10000}\u{10ffff}a"),
+            (
+                "[\u{7f}-\u{10000}]+",
+                "\u{7e}\u{7f}\u{80}\u{d7ff}\u{e000}\u{10000}\u{10001}",
+            ),
+            ("\\W+", "ab、あいう!"),
+            (".+", "a\u{3000}\u{1F642}\nb"),
+            ("(?s).+", "a\u{3000}\u{1F642}\nb"),
+            ("(?i)ä+", "xÄäÄ"),
+            ("^$", ""),
+            ("(?m)^.$", "ab\nあ\nc"),
+            ("x*", "aあ\u{1F642}"),
+        ];
+
+        for mode in [EvalMode::Depth, EvalMode::Pike, EvalMode::Dfa] {
+            for (expr, text) in inputs {
+                let re = Regex::with_mode(expr, mode).unwrap();
+                let re_b = bytes::Regex::with_mode(expr, mode).unwrap();
+                let v: Vec<_> = re.find_iter(text).map(|m| m.unwrap().range()).collect();
+                let v_b: Vec<_> = re_b
+                    .find_iter(text.as_bytes())
+                    .map(|m| m.unwrap().range())
+                    .collect();
+                assert_eq!(v, v_b, "expr = {expr}, mode = {mode:?}");
+            }
+
+            // 不正なUTF-8を含む入力
+            let re = bytes::Regex::with_mode("(.)(\\d+)", mode).unwrap();
+            let caps = re.captures(b"\xff\xfe\xe3\x81x12").unwrap().unwrap();
+            assert_eq!(&caps[0], b"x12");
+            assert_eq!(&caps[1], b"x");
+            assert_eq!(caps.get(2).unwrap().range(), 5..7);
+
+            let re = bytes::Regex::with_mode("^.*$", mode).unwrap();
+            assert!(re.is_match(b"abc").unwrap());
+            assert!(!re.is_match(b"a\xffc").unwrap());
+
+            let re = bytes::Regex::with_mode("[^a]", mode).unwrap();
+            assert!(!re.is_match(b"\x80\xc3").unwrap());
+
+            // 空文字列へのマッチは、文字の途中では行わない
+            let re = bytes::Regex::with_mode("x*", mode).unwrap();
+            let v: Vec<_> = re
+                .find_iter("あ\u{ff}".as_bytes())
+                .map(|m| m.unwrap().range())
+                .collect();
+            assert_eq!(v, vec![0..0, 3..3, 5..5]);
+
+            // 不正なUTF-8の部分では、1バイトずつ進める
+            let v: Vec<_> = re
+                .find_iter(b"\xffa\xe3\x81")
+                .map(|m| m.unwrap().range())
+                .collect();
+            assert_eq!(v, vec![0..0, 1..1, 2..2, 3..3, 4..4]);
+        }
+
+        // 幅優先探索
+        let re = RegexBuilder::new("^[あ-ん]+$")
+            .mode(EvalMode::Width)
+            .build_bytes()
+            .unwrap();
+        assert!(re.is_match("ひらがな".as_bytes()).unwrap());
+        assert!(!re.is_match("カタカナ".as_bytes()).unwrap());
+
+        // バイト単位の命令列も命令数の上限を超えない
+        let err = RegexBuilder::new("(?s).{40}")
+            .size_limit(100)
+            .build_bytes()
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CodeGenError>(),
+            Some(CodeGenError::TooLarge(100))
+        ));
+    }
+
+    #[test]
+    fn test_dfa() {
+        let m = |expr: &str, line: &str| do_matching_with(expr, line, EvalMode::Dfa);
+        assert!(m("abc|(de|cd)+", "decddede").unwrap());
+        assert!(!m("abc|(de|cd)+", "xdecd").unwrap()); // 先頭からマッチ
+        assert!(m("(a*)*b", "aaaaaaaaab").unwrap());
+
+        // a?^n a^nのような正規表現でも線形時間で終了する
+        let n = 100;
+        let expr = format!("{}{}", "a?".repeat(n), "a".repeat(n));
+        assert!(m(&expr, &"a".repeat(n)).unwrap());
+        assert!(!m(&expr, &"a".repeat(n - 1)).unwrap());
+
+        // DFAの状態はマッチングの間で共有され、Pike VMと同じ結果となる
+        let inputs = [
+            ("(ab|cd)+e", "xxabcdeabe cde"),
+            ("a|ab", "abab"),
+            ("(?m)^[a-z]+$", "abc\n123\nxyz"),
+            ("\\d+$", "12 34"),
+            ("(?i)σ+", "xΣσΣ"),
+            ("\\b[a-z]+\\b", "ab1 cd_e fg"),
+            ("\\B[a-z]+", "ab cd"),
+            ("(?m)[a-z]*$|^[0-9]", "ab\n12\ncd"),
+            ("^a+|b+", "aabab"),
+            ("[xy][a-z]+あ", "xyzあxaあ"),
+            ("\\B", "aé"),
+            ("(\\B)+", "aéb"),
+            ("\\B|é", "aéé"),
+        ];
+        for (expr, text) in inputs {
+            let pike = Regex::with_mode(expr, EvalMode::Pike).unwrap();
+            let dfa = Regex::with_mode(expr, EvalMode::Dfa).unwrap();
+            for _ in 0..2 {
+                let v: Vec<_> = pike.find_iter(text).map(|m| m.unwrap().range()).collect();
+                let v_dfa: Vec<_> = dfa.find_iter(text).map(|m| m.unwrap().range()).collect();
+                assert_eq!(v, v_dfa, "expr = {expr}");
+                assert_eq!(
+                    pike.shortest_match(text).unwrap(),
+                    dfa.shortest_match(text).unwrap()
+                );
+            }
+        }
+
+        // 空文字列へのマッチも、Pike VMと同様に文字の途中には現れない
+        for expr in ["\\B", "(\\B)+"] {
+            for mode in [EvalMode::Pike, EvalMode::Dfa] {
+                let re = Regex::with_mode(expr, mode).unwrap();
+                let m = re.find("aé").unwrap().unwrap();
+                assert_eq!((m.range(), m.as_str()), (3..3, ""), "mode = {mode:?}");
+                let re = bytes::Regex::with_mode(expr, mode).unwrap();
+                let v: Vec<_> = re
+                    .find_iter("aéé".as_bytes())
+                    .map(|m| m.unwrap().range())
+                    .collect();
+                assert_eq!(v, vec![3..3, 5..5], "mode = {mode:?}");
+            }
+        }
+
+        // 開始位置は逆向きのDFAで求め、グループの範囲はその位置からPike VMで求める
+        let re = Regex::with_mode("([a-z]+)(\\d+)?\\b", EvalMode::Dfa).unwrap();
+        let text = format!("{} abc12 x", "-".repeat(1000));
+        let caps = re.captures(&text).unwrap().unwrap();
+        assert_eq!(caps.get(0).unwrap().range(), 1001..1006);
+        assert_eq!(caps.get(1).unwrap().range(), 1001..1004);
+        assert_eq!(caps.get(2).unwrap().range(), 1004..1006);
+
+        // キャッシュの上限を超えた場合はPike VMで探索
+        let re = RegexBuilder::new("[a-z]*[a-z]{10}$")
+            .mode(EvalMode::Dfa)
+            .dfa_size_limit(4096)
+            .build()
+            .unwrap();
+        let text = "0123abcdefghijklmnopqrstuvwxyz";
+        assert_eq!(re.find(text).unwrap().unwrap().range(), 4..30);
+        assert!(re.is_match(text).unwrap());
+        assert!(!re.is_match("abc").unwrap());
+    }
+
+    #[test]
+    fn test_optimize() {
+        // 最適化後の命令列でも同じ結果となる
+        for mode in [
+            EvalMode::Depth,
+            EvalMode::Width,
+            EvalMode::Pike,
+            EvalMode::Dfa,
+        ] {
+            let m = |expr: &str, line: &str| do_matching_with(expr, line, mode);
+
+            assert!(m("abc", "abc").unwrap());
+            assert!(m("(abc|abd)e", "abde").unwrap());
+            assert!(m("((a|b)|(c|d))xy", "dxy").unwrap());
+            assert!(m("ab(cd)*ef", "abcdcdef").unwrap());
+            assert!(m("a(b(c(d)?)?)?$", "abc").unwrap());
+
+            assert!(!m("abc", "abd").unwrap());
+            assert!(!m("(abc|abd)e", "abe").unwrap());
+            assert!(!m("ab(cd)*ef", "abcdcef").unwrap());
+        }
+
+        for mode in [EvalMode::Depth, EvalMode::Pike] {
+            let re = Regex::with_mode("x(abc|ab)(c?)y|abc", mode).unwrap();
+            let caps = re.captures("zabcxabcy").unwrap().unwrap();
+            assert_eq!(caps.get(0).unwrap().range(), 1..4);
+            let caps = re.captures("xabcy").unwrap().unwrap();
+            assert_eq!(&caps[1], "abc");
+            assert_eq!(&caps[2], "");
+        }
+
+        assert!(regex::print_with("ab(c|de)*f", true).is_ok());
+    }
+
+    #[test]
+    fn test_backtrack() {
+        let depth = |expr: &str| Regex::with_mode(expr, EvalMode::Depth).unwrap();
+
+        // 指数時間かかっていた正規表現
+        let line = "a".repeat(40);
+        assert!(!depth("(a|a)*b").is_match(&line).unwrap());
+        assert!(!depth("(a*)*c").is_match(&line).unwrap());
+        assert!(!regex::do_matching_with("(a|aa)*b", &line, EvalMode::Depth).unwrap());
+
+        // 再帰でスタックが溢れていた長い入力
+        let line = "ab".repeat(100_000);
+        assert!(!depth("(a|b)*c").is_match(&line).unwrap());
+        let re = depth("(ab)*$");
+        assert_eq!(re.find(&line).unwrap().unwrap().range(), 0..line.len());
+
+        // 文字を消費しないループも停止し、Pike VMと同じ結果となる
+        for expr in ["(a?)*", "(a*)*?b", "(a|)+", "((a)|b)*", "(a*|b)*"] {
+            let pike = Regex::new(expr).unwrap();
+            let depth = depth(expr);
+            for line in ["", "a", "ab", "aab", "bab"] {
+                let caps = |re: &Regex| {
+                    re.captures(line)
+                        .unwrap()
+                        .map(|c| c.iter().map(|m| m.map(|m| m.range())).collect::<Vec<_>>())
+                };
+                assert_eq!(caps(&depth), caps(&pike), "{expr} {line}");
+            }
+        }
+
+        // 優先度は変わらない
+        let re = depth("(a|ab)(c|bcd)(d*)");
+        let caps = re.captures("abcd").unwrap().unwrap();
+        assert_eq!(&caps[1], "a");
+        assert_eq!(&caps[2], "bcd");
+        assert_eq!(&caps[3], "");
+    }
+
+    #[test]
+    fn test_backref() {
+        let depth = |expr: &str| Regex::with_mode(expr, EvalMode::Depth).unwrap();
+        let find = |expr: &str, line: &str| {
+            let m = depth(expr).find(line).unwrap();
+            m.map(|m| m.range())
+        };
+
+        // 繰り返された単語
+        let re = depth("(\\w+) \\1");
+        let caps = re.captures("this is is a pen").unwrap().unwrap();
+        assert_eq!(&caps[0], "is is");
+        assert_eq!(&caps[1], "is");
+
+        assert_eq!(find("(a+)b\\1", "aabaaa"), Some(0..5));
+        assert_eq!(find("(a|b)\\1", "abba"), Some(1..3));
+        assert_eq!(find("(a|b)\\1", "abab"), None);
+        assert_eq!(find("((a)|b)+\\2", "abba"), Some(0..4));
+        assert_eq!(find("(a*)\\1$", "aaaa"), Some(0..4));
+        assert_eq!(find("(a*)*\\1", "b"), Some(0..0));
+        assert_eq!(find("(あ|い)\\1", "あいい"), Some(3..9));
+
+        // マッチに関与していないグループへの後方参照はマッチしない
+        assert_eq!(find("(a)?b\\1", "b"), None);
+        assert_eq!(find("(a)|b\\1", "b"), None);
+
+        // 大文字と小文字を区別しない後方参照
+        assert_eq!(find("(?i)(ab)\\1", "abAB"), Some(0..4));
+        assert_eq!(find("(ab)(?i)\\1", "abAB"), Some(0..4));
+        assert_eq!(find("(ab)\\1", "abAB"), None);
+        assert_eq!(find("(?i)(k)\\1", "k\u{212a}"), Some(0..4));
+        assert_eq!(find("(?i)(\u{212a})\\1", "\u{212a}k"), Some(0..4));
+        assert_eq!(find("(?i)(σ)\\1", "σς"), Some(0..4));
+
+        // 文字列全体をそのまま評価する場合
+        assert!(do_matching("(ab|cd)\\1", "cdcd", true).unwrap());
+        assert!(!do_matching("(ab|cd)\\1", "abcd", true).unwrap());
+
+        let re = bytes::Regex::with_mode("(\\w+)=\\1", EvalMode::Depth).unwrap();
+        let m = re.find(b"\xffkey=key").unwrap().unwrap();
+        assert_eq!(m.range(), 1..8);
+
+        // 存在しないグループへの後方参照はエラー
+        for expr in ["\\1", "(a)\\2", "\\1(a)"] {
+            let e = Regex::with_mode(expr, EvalMode::Depth).unwrap_err();
+            assert!(e.downcast_ref::<ParseError>().is_some());
+        }
+
+        // 線形時間の評価器ではエラー
+        for mode in [EvalMode::Width, EvalMode::Pike, EvalMode::Dfa] {
+            let expr = "(a)\\1";
+            let e = Regex::with_mode(expr, mode).unwrap_err();
+            assert!(matches!(
+                e.downcast_ref::<EvalError>(),
+                Some(EvalError::UnsupportedBackref)
+            ));
+            assert!(do_matching_with(expr, "aa", mode).is_err());
+        }
+    }
+
+    #[test]
+    fn test_look() {
+        let depth = |expr: &str| Regex::with_mode(expr, EvalMode::Depth).unwrap();
+        let find = |expr: &str, line: &str| {
+            let m = depth(expr).find(line).unwrap();
+            m.map(|m| m.range())
+        };
+
+        // 先読み
+        assert_eq!(find("a(?=b)", "acab"), Some(2..3));
+        assert_eq!(find("a(?!b)", "abac"), Some(2..3));
+        assert_eq!(find("(?=\\d{3}$)\\d+", "12345"), Some(2..5));
+        assert_eq!(find("^(?!.*xx).*$", "axbxc"), Some(0..5));
+        assert_eq!(find("^(?!.*xx).*$", "axxc"), None);
+
+        // 後読み
+        assert_eq!(find("(?<=a)b", "bab"), Some(2..3));
+        assert_eq!(find("(?<!a)b", "abb"), Some(2..3));
+        assert_eq!(find("(?<=ab|cd)e", "abxcde"), Some(5..6));
+        assert_eq!(find("(?<=\\$)\\d+", "1 $23"), Some(3..5));
+        assert_eq!(find("(?<=a{2})b", "abaab"), Some(4..5));
+
+        // 入力の先頭では、読み戻す文字がないため肯定の後読みは失敗する
+        assert_eq!(find("(?<=a)", ""), None);
+        assert_eq!(find("(?<!a)b", "b"), Some(0..1));
+
+        // 後読みはマルチバイト文字を1文字として読み戻す
+        assert_eq!(find("(?<=あ)い", "いあい"), Some(6..9));
+        assert_eq!(find("(?<=[あa].)x", "aいx"), Some(4..5));
+
+        // 肯定の先読みの中のグループは、先読みが成功した場合のみ保存される
+        let re = depth("(?=(\\w+))\\w");
+        let caps = re.captures("  abc").unwrap().unwrap();
+        assert_eq!(&caps[0], "a");
+        assert_eq!(&caps[1], "abc");
+        let re = depth("(?!(a))\\w");
+        let caps = re.captures("ab").unwrap().unwrap();
+        assert_eq!(&caps[0], "b");
+        assert!(caps.get(1).is_none());
+
+        // 文字列全体をそのまま評価する場合
+        assert!(do_matching("(?=ab)a", "abc", true).unwrap());
+        assert!(!do_matching("(?!ab)a", "abc", true).unwrap());
+
+        let re = bytes::Regex::with_mode("(?<=\\w)=", EvalMode::Depth).unwrap();
+        let m = re.find(b"\xff=k=").unwrap().unwrap();
+        assert_eq!(m.range(), 3..4);
+
+        // 長さが一定でない後読みはエラー
+        for expr in ["(?<=a+)b", "(?<=a|bc)d", "(?<=a{1,2})b", "(?<a)b"] {
+            let e = Regex::with_mode(expr, EvalMode::Depth).unwrap_err();
+            assert!(e.downcast_ref::<ParseError>().is_some());
+        }
+
+        // 線形時間の評価器ではエラー
+        for mode in [EvalMode::Width, EvalMode::Pike, EvalMode::Dfa] {
+            let expr = "a(?=b)";
+            let e = Regex::with_mode(expr, mode).unwrap_err();
+            assert!(matches!(
+                e.downcast_ref::<EvalError>(),
+                Some(EvalError::UnsupportedLook)
+            ));
+            assert!(do_matching_with(expr, "ab", mode).is_err());
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let line = "ab".repeat(1000);
+        for mode in [EvalMode::Depth, EvalMode::Pike, EvalMode::Dfa] {
+            let build = |expr: &str, steps| {
+                RegexBuilder::new(expr)
+                    .mode(mode)
+                    .step_limit(steps)
+                    .build()
+                    .unwrap()
+            };
+
+            // 上限以内であればマッチングできる
+            let re = build("(a|b)*[cd]", 1000);
+            assert!(re.is_match("abc").unwrap());
+            assert!(!re.is_match("ab").unwrap());
+
+            // 上限を超えた場合はエラー
+            let e = re.is_match(&line).unwrap_err();
+            assert!(matches!(e, EvalError::StepLimit(1000)));
+            assert_eq!(e.to_string(), "EvalError: execution exceeds 1000 steps");
+            assert!(re.find(&line).is_err());
+
+            // ステップ数は探索ごとに数える
+            let re = build("(a|b)*[cd]", 100_000);
+            assert!(!re.is_match(&line).unwrap());
+            assert!(!re.is_match(&line).unwrap());
+
+            let re = bytes::Regex::with_mode("(a|b)*[cd]", mode).unwrap();
+            assert!(!re.is_match(line.as_bytes()).unwrap());
+        }
+
+        // 深さ優先探索のスタックの長さ
+        let build = |expr: &str, limit| {
+            RegexBuilder::new(expr)
+                .mode(EvalMode::Depth)
+                .stack_limit(limit)
+                .build()
+                .unwrap()
+        };
+        let re = build("(a|b)*[cd]", 100);
+        assert!(re.is_match("ababc").unwrap());
+        let e = re.is_match(&line).unwrap_err();
+        assert!(matches!(e, EvalError::StackLimit(100)));
+        assert_eq!(
+            e.to_string(),
+            "EvalError: backtracking stack exceeds 100 entries"
+        );
+        let re = build("(a|b)*[cd]", 100_000);
+        assert!(!re.is_match(&line).unwrap());
+
+        // 深さ優先探索で訪問済みの(pc, sp)を記録するビット集合の大きさ
+        let large = "ab".repeat(1 << 19);
+        let re = Regex::with_mode("[ab]{1000}[cd]", EvalMode::Depth).unwrap();
+        let e = re.is_match(&large).unwrap_err(); // 標準の上限は64 MiB
+        assert!(matches!(e, EvalError::VisitedLimit(0x400_0000)));
+        assert_eq!(
+            e.to_string(),
+            "EvalError: backtracking visited set exceeds 67108864 bytes"
+        );
+        assert!(!re.is_match("ab").unwrap());
+        let short = "ab".repeat(100);
+        let build = |expr: &str| {
+            RegexBuilder::new(expr)
+                .mode(EvalMode::Depth)
+                .visited_limit(100)
+                .build()
+                .unwrap()
+        };
+        let re = build("(a|b)*[cd]");
+        assert!(re.is_match("ababc").unwrap());
+        assert!(matches!(
+            re.is_match(&short),
+            Err(EvalError::VisitedLimit(100))
+        ));
+        // 後方参照を含む場合はビット集合を用いない
+        let re = build("(a)\\1|(a|b)*[cd]");
+        assert!(!re.is_match(&short).unwrap());
+
+        // 生成する命令数の上限
+        let expr = "(a|b){1000}";
+        let e = RegexBuilder::new(expr).size_limit(100).build().unwrap_err();
+        assert!(e.downcast_ref::<CodeGenError>().is_some());
+
+        // DFAのバイト単位の命令列にも上限を適用する
+        let expr = "\\p{L}{100}";
+        let build = |mode| {
+            RegexBuilder::new(expr)
+                .mode(mode)
+                .size_limit(10_000)
+                .build()
+        };
+        assert!(build(EvalMode::Pike).is_ok());
+        let e = build(EvalMode::Dfa).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CodeGenError>(),
+            Some(CodeGenError::TooLarge(10_000))
+        ));
+
+        // 命令を生成しない式の繰り返しは展開しないため、入れ子にしてもすぐにコンパイルできる
+        let expr = "^(?:(?:(?:){1000}){1000}){1000}$";
+        let re = RegexBuilder::new(expr).size_limit(100).build().unwrap();
+        assert!(re.is_match("").unwrap());
+    }
+
+    #[test]
+    fn test_prefilter() {
+        // リテラルによる絞り込みを行っても、マッチする範囲は変わらない
+        for mode in [
+            EvalMode::Depth,
+            EvalMode::Width,
+            EvalMode::Pike,
+            EvalMode::Dfa,
+        ] {
+            let find = |expr: &str, line: &str| {
+                let re = Regex::with_mode(expr, mode).unwrap();
+                re.find(line).unwrap().map(|m| m.range())
+            };
+
+            // 先頭の文字列
+            assert_eq!(find("ERROR: (a|b)+", "ERRORERROR: abc"), Some(5..14));
+            assert_eq!(find("ERROR: (a|b)+", "ERROR: c ERROR: "), None);
+            assert_eq!(find("abc|abd", "xxabxabd"), Some(5..8));
+            assert_eq!(find("(ab)+c", "abababac ababc"), Some(9..14));
+
+            // マッチ中に必ず現れる文字列
+            assert_eq!(find("x(a|b)yz", "xayxbyz"), Some(3..7));
+            assert_eq!(find("x(a|b)yz", "xay xby"), None);
+            assert_eq!(find("[0-9]+px", "12p 34px"), Some(4..8));
+
+            // アンカーやフラグとの組み合わせ
+            assert_eq!(find("^abc", "xabc"), None);
+            assert_eq!(find("(?m)^abc", "x\nabc"), Some(2..5));
+            assert_eq!(find("abc$", "abcabc"), Some(3..6));
+            assert_eq!(find("(?i)abc", "xABC"), Some(1..4));
+            assert_eq!(find("a*b?", "xyz"), Some(0..0));
+
+            let re = Regex::with_mode("ab(c|d)", mode).unwrap();
+            let v: Vec<_> = re
+                .find_iter("abcxabdabe")
+                .map(|m| m.unwrap().range())
+                .collect();
+            assert_eq!(v, vec![0..3, 4..7]);
+            assert_eq!(re.find_at("abcxabdabe", 1).unwrap().unwrap().range(), 4..7);
+
+            let re = bytes::Regex::with_mode("ab+c", mode).unwrap();
+            let m = re.find(b"\xffabbabbc").unwrap().unwrap();
+            assert_eq!(m.range(), 4..8);
+        }
+    }
 }